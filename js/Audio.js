@@ -1,105 +1,57 @@
 // This code is intentionally left unminified for your perusal
 (function() {
-class SquareChannel {
-  constructor(ctx) {
-    this.ctx = ctx;
-    this.oscillator = ctx.createOscillator();
-    this.gain = ctx.createGain();
-    this.oscillator.connect(this.gain);
-    this.oscillator.type = 'square';
-    this.oscillator.frequency.setValueAtTime(0, ctx.currentTime);
-    this.gain.gain.setValueAtTime(1, ctx.currentTime);
-    this.oscillator.start(0);
-  }
-
-  connect(node) {
-    this.gain.connect(node);
-  }
-
-  setGain(g) {
-    this.gain.gain.setValueAtTime(Math.max(g, 0.00001), this.ctx.currentTime);
-  }
-
-  setFrequency(f) {
-    this.oscillator.frequency.setValueAtTime(f, this.ctx.currentTime);
-  }
-}
-
-class NoiseChannel {
-  constructor(ctx) {
-    this.ctx = ctx;
-    const size = ctx.sampleRate; // 1s
-    const buffer = ctx.createBuffer(1, size, ctx.sampleRate);
-    const data = buffer.getChannelData(0);
-    for (let i = 0; i < size; i++) {
-      data[i] = 2 * Math.random() - 1;
-    }
-
-    this.source = ctx.createBufferSource();
-    this.source.buffer = buffer;
-    this.source.loop = true;
-    this.gain = ctx.createGain();
-    this.source.connect(this.gain);
-    this.gain.gain.setValueAtTime(0.00001, ctx.currentTime);
-    this.source.start(0);
-  }
-
-  connect(node) {
-    this.gain.connect(node);
-  }
-
-  setGain(g) {
-    this.gain.gain.setValueAtTime(Math.max(g, 0.00001), this.ctx.currentTime);
-  }
-}
+const BUFFER_SIZE = 2048;
+// Drop audio that falls further behind than this, in stereo frames
+const MAX_QUEUED = 8192;
 
+// Plays the PCM samples synthesized by the emulator. Samples arrive once per
+// emulated frame and are queued until the audio thread asks for them.
 class Audio {
   constructor() {
     this.ctx = ('AudioContext' in window) ? new AudioContext() : new webkitAudioContext();
     this.ctx.suspend();
+    this.sampleRate = this.ctx.sampleRate;
 
-    this.channels = [
-      new SquareChannel(this.ctx),
-      new SquareChannel(this.ctx),
-      null,
-      new NoiseChannel(this.ctx),
-    ];
+    this.queue = [];
+    this.queued = 0;
+    this.offset = 0;
 
-    this.leftControls = [
-      this.ctx.createGain(),
-      this.ctx.createGain(),
-      this.ctx.createGain(),
-      this.ctx.createGain(),
-    ];
-    this.rightControls = [
-      this.ctx.createGain(),
-      this.ctx.createGain(),
-      this.ctx.createGain(),
-      this.ctx.createGain(),
-    ];
-
-    this.masterLeftGain = this.ctx.createGain();
-    this.masterRightGain = this.ctx.createGain();
-    const merger = this.ctx.createChannelMerger();
-    this.masterLeftGain.connect(merger, 0, 0);
-    this.masterRightGain.connect(merger, 0, 1);
-    this.master = this.ctx.createGain();
-    merger.connect(this.master);
-    this.master.connect(this.ctx.destination);
+    this.processor = this.ctx.createScriptProcessor(BUFFER_SIZE, 0, 2);
+    this.processor.onaudioprocess = e => this.fill(e.outputBuffer);
+    this.processor.connect(this.ctx.destination);
+  }
 
-    for (let i = 0; i < 4; i++) {
-      if (this.channels[i]) {
-        this.channels[i].connect(this.leftControls[i], 0);
-        this.channels[i].connect(this.rightControls[i], 1);
-        this.leftControls[i].connect(this.masterLeftGain);
-        this.rightControls[i].connect(this.masterRightGain);
-      }
+  // Interleaved stereo samples, copied since the emulator reuses its buffer
+  push(samples) {
+    this.queue.push(new Float32Array(samples));
+    this.queued += samples.length / 2;
+    while (this.queued - this.offset / 2 > MAX_QUEUED && this.queue.length > 1) {
+      const dropped = this.queue.shift();
+      this.queued -= dropped.length / 2;
+      this.offset = 0;
     }
   }
 
-  setMasterGain(left, right) {
-    this.masterLeftGain.gain.setValueAtTime(Math.max(left, 0.00001), this.ctx.currentTime);
-    this.masterRightGain.gain.setValueAtTime(Math.max(right, 0.00001), this.ctx.currentTime);
+  fill(output) {
+    const left = output.getChannelData(0);
+    const right = output.getChannelData(1);
+    for (let i = 0; i < left.length; i++) {
+      if (this.queue.length === 0) {
+        // Starved, play silence
+        left[i] = 0;
+        right[i] = 0;
+        continue;
+      }
+      const chunk = this.queue[0];
+      left[i] = chunk[this.offset];
+      right[i] = chunk[this.offset + 1];
+      this.offset += 2;
+      if (this.offset >= chunk.length) {
+        this.queue.shift();
+        this.queued -= chunk.length / 2;
+        this.offset = 0;
+      }
+    }
   }
 
   pause() {
@@ -109,15 +61,7 @@ class Audio {
   play() {
     this.ctx.resume();
   }
-
-  enableAudio(flag) {
-    if (flag) {
-      this.master.gain.setValueAtTime(1.0, this.ctx.currentTime);
-    } else {
-      this.master.gain.setValueAtTime(0.00001, this.ctx.currentTime);
-    }
-  }
 }
 
 window.Audio = Audio;
-})();
\ No newline at end of file
+})();
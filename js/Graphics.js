@@ -1,20 +1,25 @@
 // This code is intentionally left unminified for your perusal
 (function() {
 
+// Default shades, replaced by the palette configured in the emulator
 const COLORS = [
-  [0x9b, 0xbc, 0x0f],
-  [0x8b, 0xac, 0x0f],
-  [0x30, 0x62, 0x30],
-  [0x0f, 0x38, 0x0f],
+  0x9bbc0f,
+  0x8bac0f,
+  0x306230,
+  0x0f380f,
+  0x9bbc0f,
+  0x8bac0f,
+  0x306230,
+  0x0f380f,
+  0x9bbc0f,
+  0x8bac0f,
+  0x306230,
+  0x0f380f,
 ];
 
-const GL_COLORS = [];
-for (let i = 0; i < COLORS.length; i++) {
-  GL_COLORS[i] = [];
-  for (let j = 0; j < 3; j++) {
-    GL_COLORS[i][j] = COLORS[i][j] / 255;
-  }
-}
+const PALETTE_BG = 0;
+const PALETTE_OBP0 = 4;
+const PALETTE_OBP1 = 8;
 
 const SPRITE_COUNT = 40;
 
@@ -228,6 +233,29 @@ void main() {
 }
 `;
 
+const framebufferVertexShaderSource = `#version 300 es
+in vec4 a_position;
+out vec2 v_texcoord;
+
+void main() {
+  gl_Position = a_position * 2.0 - vec4(1, 1, 0, 1);
+  v_texcoord = vec2(a_position.x, 1.0 - a_position.y);
+}
+`;
+
+const framebufferFragmentShaderSource = `#version 300 es
+precision highp float;
+
+in vec2 v_texcoord;
+uniform sampler2D u_texture;
+
+out vec4 outColor;
+
+void main() {
+  outColor = texture(u_texture, v_texcoord);
+}
+`;
+
 function createShader(gl, type, source) {
   const shader = gl.createShader(type);
   gl.shaderSource(shader, source);
@@ -251,28 +279,23 @@ function createProgram(gl, vertexShader, fragmentShader) {
   gl.deleteProgram(program);
 }
 
-function updateGLColors(palette, colors, skipZero) {
+function setGLColor(colors, offset, rgb) {
+  colors[offset] = ((rgb >> 16) & 0xff) / 255;
+  colors[offset + 1] = ((rgb >> 8) & 0xff) / 255;
+  colors[offset + 2] = (rgb & 0xff) / 255;
+}
+
+function updateGLColors(palette, colors, skipZero, shades, layer) {
   const c0 = palette & 3;
   const c1 = (palette >> 2) & 3;
   const c2 = (palette >> 4) & 3;
   const c3 = (palette >> 6) & 3;
   if (!skipZero) {
-    colors[0] = GL_COLORS[c0][0];
-    colors[1] = GL_COLORS[c0][1];
-    colors[2] = GL_COLORS[c0][2];
+    setGLColor(colors, 0, shades[layer + c0]);
   }
-
-  colors[4] = GL_COLORS[c1][0];
-  colors[5] = GL_COLORS[c1][1];
-  colors[6] = GL_COLORS[c1][2];
-
-  colors[8] = GL_COLORS[c2][0];
-  colors[9] = GL_COLORS[c2][1];
-  colors[10] = GL_COLORS[c2][2];
-
-  colors[12] = GL_COLORS[c3][0];
-  colors[13] = GL_COLORS[c3][1];
-  colors[14] = GL_COLORS[c3][2];
+  setGLColor(colors, 4, shades[layer + c1]);
+  setGLColor(colors, 8, shades[layer + c2]);
+  setGLColor(colors, 12, shades[layer + c3]);
 }
 
 class Graphics {
@@ -322,6 +345,21 @@ class Graphics {
       },
     };
 
+    const framebufferProgram = createProgram(
+      gl,
+      createShader(gl, gl.VERTEX_SHADER, framebufferVertexShaderSource),
+      createShader(gl, gl.FRAGMENT_SHADER, framebufferFragmentShaderSource),
+    );
+    this.framebuffer = {
+      program: framebufferProgram,
+      attributes: {
+        position: gl.getAttribLocation(framebufferProgram, 'a_position'),
+      },
+      uniforms: {
+        texture: gl.getUniformLocation(framebufferProgram, 'u_texture'),
+      },
+    };
+
     const positionBuffer = gl.createBuffer();
     gl.bindBuffer(gl.ARRAY_BUFFER, positionBuffer);
     // Big quad across the screen
@@ -338,6 +376,9 @@ class Graphics {
     gl.enableVertexAttribArray(this.window.attributes.position);
     gl.vertexAttribPointer(this.window.attributes.position, 2, gl.FLOAT, false, 0, 0);
 
+    gl.enableVertexAttribArray(this.framebuffer.attributes.position);
+    gl.vertexAttribPointer(this.framebuffer.attributes.position, 2, gl.FLOAT, false, 0, 0);
+
     this.tile0Texture = gl.createTexture();
     this.tile1Texture = gl.createTexture();
     gl.activeTexture(gl.TEXTURE0 + 0);
@@ -361,6 +402,14 @@ class Graphics {
     gl.texParameteri(gl.TEXTURE_2D, gl.TEXTURE_WRAP_S, gl.CLAMP_TO_EDGE);
     gl.texParameteri(gl.TEXTURE_2D, gl.TEXTURE_WRAP_T, gl.CLAMP_TO_EDGE);
 
+    this.framebufferTexture = gl.createTexture();
+    gl.activeTexture(gl.TEXTURE0 + 5);
+    gl.bindTexture(gl.TEXTURE_2D, this.framebufferTexture);
+    gl.texParameteri(gl.TEXTURE_2D, gl.TEXTURE_MIN_FILTER, gl.NEAREST);
+    gl.texParameteri(gl.TEXTURE_2D, gl.TEXTURE_MAG_FILTER, gl.NEAREST);
+    gl.texParameteri(gl.TEXTURE_2D, gl.TEXTURE_WRAP_S, gl.CLAMP_TO_EDGE);
+    gl.texParameteri(gl.TEXTURE_2D, gl.TEXTURE_WRAP_T, gl.CLAMP_TO_EDGE);
+
     const spriteProgram = createProgram(
       gl,
       createShader(gl, gl.VERTEX_SHADER, spriteVertexShaderSource),
@@ -458,13 +507,43 @@ class Graphics {
     gl.texImage2D(gl.TEXTURE_2D, 0, gl.RG8UI, 8, 384, 0, gl.RG_INTEGER, gl.UNSIGNED_BYTE, data);
   }
 
-  draw(spriteTable, zeroPage) {
-    updateGLColors(zeroPage[0x47], this.bgp, false);
-    updateGLColors(zeroPage[0x48], this.obp0, true);
-    updateGLColors(zeroPage[0x49], this.obp1, true);
+  draw(spriteTable, zeroPage, shades) {
+    shades = shades || COLORS;
+    updateGLColors(zeroPage[0x47], this.bgp, false, shades, PALETTE_BG);
+    updateGLColors(zeroPage[0x48], this.obp0, true, shades, PALETTE_OBP0);
+    updateGLColors(zeroPage[0x49], this.obp1, true, shades, PALETTE_OBP1);
     this._draw(zeroPage[0x40], zeroPage[0x43], zeroPage[0x42], zeroPage[0x4b], zeroPage[0x4a]);
   }
 
+  // Resizes the canvas to show a width x height screen at twice the size
+  setScreenSize(width, height) {
+    const canvas = this.gl.canvas;
+    if (canvas.width === width * 2 && canvas.height === height * 2) {
+      return;
+    }
+    canvas.width = width * 2;
+    canvas.height = height * 2;
+    canvas.style.width = `${width * 2}px`;
+  }
+
+  // Draws a frame already rendered by the emulator as RGBA pixels. Used in
+  // CGB mode, where tiles, attributes and palettes live in banked memory the
+  // shaders don't see, and for the 256x224 SGB screen with its border. It's
+  // shown at twice the size unless the target is given.
+  drawFramebuffer(pixels, width, height, targetWidth = width * 2, targetHeight = height * 2) {
+    const gl = this.gl;
+    gl.viewport(0, 0, targetWidth, targetHeight);
+    gl.activeTexture(gl.TEXTURE0 + 5);
+    gl.bindTexture(gl.TEXTURE_2D, this.framebufferTexture);
+    gl.pixelStorei(gl.UNPACK_ALIGNMENT, 1);
+    gl.texImage2D(gl.TEXTURE_2D, 0, gl.RGBA8, width, height, 0, gl.RGBA, gl.UNSIGNED_BYTE, pixels);
+
+    gl.useProgram(this.framebuffer.program);
+    gl.bindVertexArray(this.vao);
+    gl.uniform1i(this.framebuffer.uniforms.texture, 5);
+    gl.drawArrays(gl.TRIANGLES, 0, 6);
+  }
+
   _draw(control, offsetX, offsetY, windowX, windowY) {
     const colors = this.bgp;
     const obp0 = this.obp0;
@@ -47,27 +47,6 @@ function loadWASM(vm) {
       log_addr: function(addr, value) {
         //console.log('addr', addr.toString(16), value.toString(16));
         writes[addr.toString(16)] = value.toString(16);
-      },
-      set_channel_1_freq: function(freq, volume) {
-        vm.audio.channels[0].setFrequency(freq);
-      },
-      set_channel_1_gain: function(volume) {
-        vm.audio.channels[0].setGain(volume / 15);
-      },
-      set_channel_2_freq: function(freq, volume) {
-        vm.audio.channels[1].setFrequency(freq);
-      },
-      set_channel_2_gain: function(volume) {
-        vm.audio.channels[1].setGain(volume / 15);
-      },
-      set_channel_4_gain: function(volume) {
-        vm.audio.channels[3].setGain(volume / 15);
-      },
-      set_master_gain: function(left, right) {
-        vm.audio.setMasterGain(left / 15, right / 15);
-      },
-      audio_enabled: function(flag) {
-        vm.audio.enableAudio(!!flag);
       }
     },
   }).then(instance => {
@@ -80,15 +59,49 @@ function loadWASM(vm) {
       getVRamPointer: instance.exports.get_vram_pointer,
       getSpriteTablePointer: instance.exports.get_sprite_table_pointer,
       getZeroPagePointer: instance.exports.get_zero_page_pointer,
+      getPalettePointer: instance.exports.get_palette_pointer,
+      getFramebufferPointer: instance.exports.get_framebuffer_pointer,
+      isCgbMode: instance.exports.is_cgb_mode,
+      isSgbMode: instance.exports.is_sgb_mode,
+      setSgbMode: instance.exports.set_sgb_mode,
+      getSgbFramebufferPointer: instance.exports.get_sgb_framebuffer_pointer,
+      setPalettePreset: instance.exports.set_palette_preset,
       frame: instance.exports.frame,
       reset: instance.exports.reset,
       resetAfterBootloader: instance.exports.reset_after_bootloader,
+      resetWithBootRom: instance.exports.reset_with_boot_rom,
+      setModel: instance.exports.set_model,
+      powerOn: instance.exports.power_on,
       keyDown: instance.exports.key_down,
       keyUp: instance.exports.key_up,
       setButtons: instance.exports.set_buttons,
       setDirections: instance.exports.set_directions,
+      setPlayerButtons: instance.exports.set_player_buttons,
+      setPlayerDirections: instance.exports.set_player_directions,
       setMBC: instance.exports.set_mbc,
+      applyCartHeader: instance.exports.apply_cart_header,
       isSramDirty: instance.exports.is_sram_dirty,
+      setAudioSampleRate: instance.exports.set_audio_sample_rate,
+      drainAudio: instance.exports.drain_audio,
+      getAudioBufferPointer: instance.exports.get_audio_buffer_pointer,
+      loadGbs: instance.exports.load_gbs,
+      gbsFrame: instance.exports.gbs_frame,
+      startGbsTrack: instance.exports.start_gbs_track,
+      getGbsTrack: instance.exports.get_gbs_track,
+      setGbsDuration: instance.exports.set_gbs_duration,
+      startVgmLog: instance.exports.start_vgm_log,
+      stopVgmLog: instance.exports.stop_vgm_log,
+      getVgmLogPointer: instance.exports.get_vgm_log_pointer,
+      setChannelMute: instance.exports.set_channel_mute,
+      setChannelSolo: instance.exports.set_channel_solo,
+      setChannelGain: instance.exports.set_channel_gain,
+      startAudioRecording: instance.exports.start_audio_recording,
+      stopAudioRecording: instance.exports.stop_audio_recording,
+      encodeAudioRecording: instance.exports.encode_audio_recording,
+      getAudioRecordingPointer: instance.exports.get_audio_recording_pointer,
+      enableSerialCapture: instance.exports.enable_serial_capture,
+      takeSerialOutput: instance.exports.take_serial_output,
+      getSerialOutputPointer: instance.exports.get_serial_output_pointer,
     };
   });
 }
@@ -130,12 +143,14 @@ class VM {
     this.mem = null;
     this.gb = null;
     this._lastSave = 0;
+    this.gbsSongs = 0;
 
     this._ready = loadWASM(this);
     this._ready.then(mod => {
       this.mod = mod;
 
       this.gb = mod.createVM();
+      mod.setAudioSampleRate(this.gb, this.audio.sampleRate);
       const mem = {
         bootPtr: mod.getBootPointer(this.gb),
         romPtr: mod.getRomPointer(this.gb),
@@ -143,14 +158,20 @@ class VM {
         vramPtr: mod.getVRamPointer(this.gb),
         spriteTablePtr: mod.getSpriteTablePointer(this.gb),
         zeroPagePtr: mod.getZeroPagePointer(this.gb),
+        palettePtr: mod.getPalettePointer(this.gb),
+        framebufferPtr: mod.getFramebufferPointer(this.gb),
+        sgbFramebufferPtr: mod.getSgbFramebufferPointer(this.gb),
       };
       const buffer = mod.memory.buffer;
-      mem.boot = new Uint8Array(buffer, mem.bootPtr, 0x100);
+      mem.boot = new Uint8Array(buffer, mem.bootPtr, 0x900);
       mem.rom = new Uint8Array(buffer, mem.romPtr, 0x200000);
       mem.ram = new Uint8Array(buffer, mem.ramPtr, 0x8000);
       mem.vram = new Uint8Array(buffer, mem.vramPtr, 0x2000);
       mem.spriteTable = new Uint8Array(buffer, mem.spriteTablePtr, 0xa0);
       mem.zeroPage = new Uint8Array(buffer, mem.zeroPagePtr, 0x100);
+      mem.palette = new Uint32Array(buffer, mem.palettePtr, 12);
+      mem.framebuffer = new Uint8Array(buffer, mem.framebufferPtr, 160 * 144 * 4);
+      mem.sgbFramebuffer = new Uint8Array(buffer, mem.sgbFramebufferPtr, 256 * 224 * 4);
       this.mem = mem;
 
       this.vramWindows = {
@@ -164,6 +185,11 @@ class VM {
   }
 
   frame(ms) {
+    if (this.gbsSongs > 0) {
+      this.gbsFrame();
+      return;
+    }
+
     // Periodic SRAM save
     if (this._lastSave === 0) {
       this._lastSave = ms;
@@ -188,6 +214,7 @@ class VM {
     }
 
     const state = this.mod.frame(this.gb);
+    this.drainAudio();
     let inVR = false;
 
     if (this.vr && this.vr.active) {
@@ -206,6 +233,29 @@ class VM {
     }
   }
 
+  gbsFrame() {
+    const state = this.mod.gbsFrame(this.gb);
+    this.drainAudio();
+    if (state === 1) {
+      this._raf = null;
+      this._playing = false;
+      return;
+    }
+    if (state === 2) {
+      this.nextTrack();
+    }
+    this._raf = requestAnimationFrame(this.frame);
+  }
+
+  drainAudio() {
+    const frames = this.mod.drainAudio(this.gb);
+    if (frames > 0) {
+      const ptr = this.mod.getAudioBufferPointer(this.gb);
+      const samples = new Float32Array(this.mod.memory.buffer, ptr, frames * 2);
+      this.audio.push(samples);
+    }
+  }
+
   copyTileData() {
     if (this.vramWindows) {
       this.graphics.loadTileData(this.vramWindows.tileData);
@@ -230,10 +280,29 @@ class VM {
     } else {
       gl.bindFramebuffer(gl.FRAMEBUFFER, null);
     }
-    if (this.vramWindows) {
+    if (!this.vramWindows) {
+      return;
+    }
+    const presenting = this.vr && this.vr.state.isPresenting();
+    if (this.mod.isSgbMode(this.gb)) {
+      if (presenting) {
+        // Squeezed into the VR screen texture
+        this.graphics.drawFramebuffer(this.mem.sgbFramebuffer, 256, 224, 320, 288);
+      } else {
+        this.graphics.setScreenSize(256, 224);
+        this.graphics.drawFramebuffer(this.mem.sgbFramebuffer, 256, 224);
+      }
+      return;
+    }
+    if (!presenting) {
+      this.graphics.setScreenSize(160, 144);
+    }
+    if (this.mod.isCgbMode(this.gb)) {
+      this.graphics.drawFramebuffer(this.mem.framebuffer, 160, 144);
+    } else {
       this.graphics.loadBGTiles(this.vramWindows.bgTile0, this.vramWindows.bgTile1);
       this.graphics.loadOAMData(this.mem.spriteTable);
-      this.graphics.draw(this.mem.spriteTable, this.mem.zeroPage);
+      this.graphics.draw(this.mem.spriteTable, this.mem.zeroPage, this.mem.palette);
     }
   }
 
@@ -245,17 +314,24 @@ class VM {
     if (this._playing) {
       this.pause();
     }
+    this.gbsSongs = 0;
+    // The ROM goes in first, skipping the boot ROM depends on its header
+    if (rom) {
+      memcpy(this.mem.rom, rom, 0);
+    } else {
+      memcpy(this.mem.rom, ROM_HEAD, 0x100);
+    }
     if (DMG_ROM.length > 0) {
-      this.mod.reset();
       memcpy(this.mem.boot, DMG_ROM, 0);
+      this.mod.resetWithBootRom(this.gb, DMG_ROM.length);
     } else {
       this.mod.resetAfterBootloader(this.gb);
     }
     if (rom) {
-      memcpy(this.mem.rom, rom, 0);
       // Extract the MBC ID
       const mbc = this.mem.rom[0x147];
       this.mod.setMBC(this.gb, mbc);
+      this.mod.applyCartHeader(this.gb);
       this.saveState.load().then(() => {
         console.log('Loaded save state from IndexedDB');
         this.play();
@@ -263,8 +339,6 @@ class VM {
         console.log('No save file exists for this ROM');
         this.play();
       });
-    } else {
-      memcpy(this.mem.rom, ROM_HEAD, 0x100);
     }
   }
 
@@ -290,6 +364,133 @@ class VM {
   pause() {
 
   }
+
+  // Plays a .gbs music rip instead of a cart, returns the number of songs
+  loadGBS(data) {
+    if (this._playing) {
+      this.pause();
+    }
+    memcpy(this.mem.rom, data, 0);
+    this.gbsSongs = this.mod.loadGbs(this.gb, data.length);
+    if (this.gbsSongs > 0) {
+      this.play();
+    }
+    return this.gbsSongs;
+  }
+
+  // 0-based track number
+  selectTrack(track) {
+    this.mod.startGbsTrack(this.gb, track);
+  }
+
+  nextTrack() {
+    const track = this.mod.getGbsTrack(this.gb);
+    this.selectTrack((track + 1) % this.gbsSongs);
+  }
+
+  previousTrack() {
+    const track = this.mod.getGbsTrack(this.gb);
+    this.selectTrack((track + this.gbsSongs - 1) % this.gbsSongs);
+  }
+
+  // Seconds before moving on to the next track, 0 plays forever
+  setTrackDuration(seconds) {
+    this.mod.setGbsDuration(this.gb, seconds);
+  }
+
+  setPalettePreset(preset) {
+    this.mod.setPalettePreset(this.gb, preset);
+  }
+
+  // Turns SGB packets, colorization and the border on or off without changing
+  // the model
+  setSgbMode(enabled) {
+    this.mod.setSgbMode(this.gb, enabled ? 1 : 0);
+  }
+
+  // Hardware to emulate from the next reset on, see model_from_index
+  setModel(model) {
+    this.mod.setModel(this.gb, model);
+  }
+
+  // Fills RAM with garbage before the next reset: 0 zeroes, 1 random,
+  // 2 hardware-like, reproducible from the seed. With a boot ROM, that reset
+  // garbles the CPU registers too.
+  powerOn(init, seed) {
+    this.mod.powerOn(this.gb, init, seed);
+  }
+
+  // Logs APU register writes until stopVgmLog, which returns a VGM file
+  startVgmLog() {
+    this.mod.startVgmLog(this.gb);
+  }
+
+  stopVgmLog() {
+    const size = this.mod.stopVgmLog(this.gb);
+    if (size === 0) {
+      return null;
+    }
+    const ptr = this.mod.getVgmLogPointer(this.gb);
+    const bytes = new Uint8Array(this.mod.memory.buffer, ptr, size);
+    return new Blob([bytes.slice()], {type: 'audio/x-vgm'});
+  }
+
+  // Mixer controls for channels 1-4, games can't tell they're in use
+  setChannelMute(channel, muted) {
+    this.mod.setChannelMute(this.gb, channel, muted ? 1 : 0);
+  }
+
+  setChannelSolo(channel, solo) {
+    this.mod.setChannelSolo(this.gb, channel, solo ? 1 : 0);
+  }
+
+  setChannelGain(channel, gain) {
+    this.mod.setChannelGain(this.gb, channel, gain);
+  }
+
+  // Records audio until stopAudioRecording, with a separate track per channel
+  // if stems is set
+  startAudioRecording(stems) {
+    this.mod.startAudioRecording(this.gb, stems ? 1 : 0);
+  }
+
+  // Returns WAV blobs of the mix, followed by channels 1-4 if stems were
+  // recorded
+  stopAudioRecording() {
+    this.mod.stopAudioRecording(this.gb);
+    const files = [];
+    for (let track = 0; track < 5; track++) {
+      const size = this.mod.encodeAudioRecording(this.gb, track);
+      if (size === 0) {
+        break;
+      }
+      const ptr = this.mod.getAudioRecordingPointer(this.gb);
+      const bytes = new Uint8Array(this.mod.memory.buffer, ptr, size);
+      files.push(new Blob([bytes.slice()], {type: 'audio/wav'}));
+    }
+    return files;
+  }
+
+  // Keeps what the game sends over serial, test ROMs report results that way
+  enableSerialCapture(enabled) {
+    this.mod.enableSerialCapture(this.gb, enabled ? 1 : 0);
+  }
+
+  // Returns the bytes sent since the last call
+  takeSerialOutput() {
+    const size = this.mod.takeSerialOutput(this.gb);
+    const ptr = this.mod.getSerialOutputPointer(this.gb);
+    return new Uint8Array(this.mod.memory.buffer, ptr, size).slice();
+  }
+
+  // Pads for players 2-4, only read by SGB multiplayer games
+  setPlayerButtons(player, buttons) {
+    this.mod.setPlayerButtons(this.gb, player, buttons);
+  }
+
+  setPlayerDirections(player, directions) {
+    this.mod.setPlayerDirections(this.gb, player, directions);
+  }
 }
 
 window.VM = VM;
@@ -4,6 +4,7 @@ mod vm;
 
 use std::mem;
 use vm::VM;
+use vm::link::Link;
 
 extern "C" {
   fn update_registers(a: u8, b: u8, c: u8, d: u8, e: u8, h: u8, l: u8, flags: u8, sp: u16, pc: u16);
@@ -11,17 +12,46 @@ extern "C" {
 
 #[no_mangle]
 pub fn create_vm() -> *mut VM {
-  let vm = VM {
-    cpu: vm::cpu::create_cpu(),
-    gpu: vm::gpu::create_gpu(),
-    mem: vm::memmap::create_memmap(0),
-    state: vm::cpu::RunState::Run,
-    breakpoints: vec![0x100],
-  };
-  let b = Box::new(vm);
+  let b = Box::new(vm::create_vm());
   return Box::into_raw(b);
 }
 
+// Both VMs must be distinct, a VM can't be linked to itself. Returns null
+// if they're the same.
+#[no_mangle]
+pub fn create_link(raw_a: *mut VM, raw_b: *mut VM) -> *mut Link {
+  if raw_a == raw_b {
+    return std::ptr::null_mut();
+  }
+  unsafe {
+    let mut a = Box::from_raw(raw_a);
+    let mut b = Box::from_raw(raw_b);
+    let link = Box::new(vm::link::create_link(&mut a, &mut b));
+    mem::forget(a);
+    mem::forget(b);
+    return Box::into_raw(link);
+  }
+}
+
+// Takes the VMs in the order the link was created with. Returns 0 without
+// running anything if they aren't the ones plugged into it.
+#[no_mangle]
+pub fn link_frame(raw: *mut Link, raw_a: *mut VM, raw_b: *mut VM) -> u8 {
+  if raw_a == raw_b {
+    return 0;
+  }
+  unsafe {
+    let mut link = Box::from_raw(raw);
+    let mut a = Box::from_raw(raw_a);
+    let mut b = Box::from_raw(raw_b);
+    let breakpoint = link.is_attached(&mut a, &mut b) && link.frame(&mut a, &mut b);
+    mem::forget(link);
+    mem::forget(a);
+    mem::forget(b);
+    return if breakpoint { 1 } else { 0 };
+  }
+}
+
 #[no_mangle]
 pub fn reset(raw: *mut VM) {
   unsafe {
@@ -31,13 +61,130 @@ pub fn reset(raw: *mut VM) {
   }
 }
 
+// Skips the boot ROM, leaving registers the way the selected model's would,
+// or the model the cart header asks for. Copy the ROM in first.
 #[no_mangle]
 pub fn reset_after_bootloader(raw: *mut VM) {
   unsafe {
     let mut vm = Box::from_raw(raw);
-    vm.cpu.reset();
-    vm.cpu.simulate_bootloader();
-    vm.mem.simulate_bootloader();
+    let model = vm.cart_model();
+    vm.reset_after_bootloader(model);
+    mem::forget(vm);
+  }
+}
+
+// Skips the boot ROM, leaving registers the way the given model's would
+#[no_mangle]
+pub fn reset_after_bootloader_for_model(raw: *mut VM, model: u8) {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    let model = vm::model::model_from_index(model).unwrap_or(vm::model::Model::Dmg);
+    vm.reset_after_bootloader(model);
+    mem::forget(vm);
+  }
+}
+
+// Fills RAM with zeroes (0), seeded random bytes (1) or a hardware-like
+// pattern (2), before resetting
+#[no_mangle]
+pub fn power_on(raw: *mut VM, init: u8, seed: u32) {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    match vm::random::ram_init_from_index(init) {
+      Some(i) => vm.power_on(i, seed),
+      None => (),
+    };
+    mem::forget(vm);
+  }
+}
+
+// Picks the hardware to emulate on the next reset, 0xff picks it from the
+// cart header
+#[no_mangle]
+pub fn set_model(raw: *mut VM, model: u8) {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    vm.model = vm::model::model_from_index(model);
+    mem::forget(vm);
+  }
+}
+
+// Runs the boot ROM previously copied to the boot pointer
+#[no_mangle]
+pub fn reset_with_boot_rom(raw: *mut VM, len: u32) {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    vm.reset_with_boot_rom(len as usize);
+    mem::forget(vm);
+  }
+}
+
+// Plays the GBS file previously copied to the ROM pointer, returning its
+// number of songs, or 0 if it couldn't be loaded
+#[no_mangle]
+pub fn load_gbs(raw: *mut VM, len: u32) -> u8 {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    let len = (len as usize).min(vm.mem.cart.raw_rom.len());
+    let data = vm.mem.cart.raw_rom[..len].to_vec();
+    let songs = match vm.load_gbs(&data) {
+      Ok(_) => vm.gbs.as_ref().unwrap().header.song_count,
+      Err(_) => 0,
+    };
+    mem::forget(vm);
+    return songs;
+  }
+}
+
+// 0 while playing, 1 on a crash, 2 once the track's duration has passed
+#[no_mangle]
+pub fn gbs_frame(raw: *mut VM) -> i32 {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    let finished = vm.gbs_frame();
+    let state = if vm.state == vm::cpu::RunState::Crash {
+      1
+    } else if finished {
+      2
+    } else {
+      0
+    };
+    mem::forget(vm);
+    return state;
+  }
+}
+
+// 0-based
+#[no_mangle]
+pub fn start_gbs_track(raw: *mut VM, track: u8) {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    vm.start_gbs_track(track);
+    mem::forget(vm);
+  }
+}
+
+#[no_mangle]
+pub fn get_gbs_track(raw: *mut VM) -> u8 {
+  unsafe {
+    let vm = Box::from_raw(raw);
+    let track = match vm.gbs {
+      Some(ref player) => player.track,
+      None => 0,
+    };
+    mem::forget(vm);
+    return track;
+  }
+}
+
+// Seconds each track plays for, 0 plays forever
+#[no_mangle]
+pub fn set_gbs_duration(raw: *mut VM, seconds: u32) {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    if let Some(ref mut player) = vm.gbs {
+      player.duration = seconds;
+    }
     mem::forget(vm);
   }
 }
@@ -169,6 +316,373 @@ pub fn get_zero_page_pointer(raw: *mut VM) -> *mut u8 {
   }
 }
 
+#[no_mangle]
+pub fn get_framebuffer_pointer(raw: *mut VM) -> *mut u8 {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    let ptr = vm.gpu.framebuffer_ptr();
+    mem::forget(vm);
+    return ptr;
+  }
+}
+
+// 1 when the framebuffer should be drawn instead of the raw VRAM, since the
+// cart runs with CGB banks and palettes
+#[no_mangle]
+pub fn is_cgb_mode(raw: *mut VM) -> u8 {
+  unsafe {
+    let vm = Box::from_raw(raw);
+    let value = if vm.mem.cgb_mode { 1 } else { 0 };
+    mem::forget(vm);
+    return value;
+  }
+}
+
+// 1 when the SGB framebuffer, with its border and colorization, should be
+// drawn instead of the Game Boy screen
+#[no_mangle]
+pub fn is_sgb_mode(raw: *mut VM) -> u8 {
+  unsafe {
+    let vm = Box::from_raw(raw);
+    let value = if vm.mem.sgb.enabled { 1 } else { 0 };
+    mem::forget(vm);
+    return value;
+  }
+}
+
+// 256x224 RGBA pixels
+#[no_mangle]
+pub fn get_sgb_framebuffer_pointer(raw: *mut VM) -> *mut u8 {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    let ptr = vm.mem.sgb.framebuffer_ptr();
+    mem::forget(vm);
+    return ptr;
+  }
+}
+
+#[no_mangle]
+pub fn set_sgb_mode(raw: *mut VM, flag: u8) {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    vm.mem.sgb.enabled = flag != 0;
+    mem::forget(vm);
+  }
+}
+
+#[no_mangle]
+pub fn set_audio_sample_rate(raw: *mut VM, rate: u32) {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    vm.mem.audio.set_sample_rate(rate);
+    mem::forget(vm);
+  }
+}
+
+// Moves the synthesized audio to the audio buffer pointer, returning the
+// number of interleaved stereo frames waiting there
+#[no_mangle]
+pub fn drain_audio(raw: *mut VM) -> u32 {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    let frames = vm.mem.audio.drain() as u32;
+    mem::forget(vm);
+    return frames;
+  }
+}
+
+// Only valid until the next drain_audio call
+#[no_mangle]
+pub fn get_audio_buffer_pointer(raw: *mut VM) -> *mut f32 {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    let ptr = vm.mem.audio.output_ptr();
+    mem::forget(vm);
+    return ptr;
+  }
+}
+
+// Logs APU register writes with their timing until stop_vgm_log
+#[no_mangle]
+pub fn start_vgm_log(raw: *mut VM) {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    vm.mem.start_vgm_log();
+    mem::forget(vm);
+  }
+}
+
+// Writes the VGM file to the VGM log pointer, returning its size in bytes
+#[no_mangle]
+pub fn stop_vgm_log(raw: *mut VM) -> u32 {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    let size = match vm.mem.stop_vgm_log() {
+      Some(vgm) => {
+        vm.mem.vgm_output = vgm;
+        vm.mem.vgm_output.len() as u32
+      },
+      None => 0,
+    };
+    mem::forget(vm);
+    return size;
+  }
+}
+
+#[no_mangle]
+pub fn get_vgm_log_pointer(raw: *mut VM) -> *mut u8 {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    let ptr = vm.mem.vgm_output.as_mut_ptr();
+    mem::forget(vm);
+    return ptr;
+  }
+}
+
+// Mixer controls for channels 1-4, leaving the registers games read alone
+#[no_mangle]
+pub fn set_channel_mute(raw: *mut VM, channel: u8, flag: u8) {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    vm.mem.audio.set_channel_muted(channel as usize, flag != 0);
+    mem::forget(vm);
+  }
+}
+
+#[no_mangle]
+pub fn set_channel_solo(raw: *mut VM, channel: u8, flag: u8) {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    vm.mem.audio.set_channel_solo(channel as usize, flag != 0);
+    mem::forget(vm);
+  }
+}
+
+#[no_mangle]
+pub fn set_channel_gain(raw: *mut VM, channel: u8, gain: f32) {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    vm.mem.audio.set_channel_gain(channel as usize, gain);
+    mem::forget(vm);
+  }
+}
+
+// Records every mixed frame from here on, with a stem per channel if asked
+#[no_mangle]
+pub fn start_audio_recording(raw: *mut VM, stems: u8) {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    vm.mem.audio.start_recording(stems != 0);
+    mem::forget(vm);
+  }
+}
+
+// Returns the number of stereo frames recorded
+#[no_mangle]
+pub fn stop_audio_recording(raw: *mut VM) -> u32 {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    let frames = vm.mem.audio.stop_recording() as u32;
+    mem::forget(vm);
+    return frames;
+  }
+}
+
+// Encodes the recorded mix (track 0) or channel 1-4 as a WAV file at the
+// audio recording pointer, returning its size in bytes
+#[no_mangle]
+pub fn encode_audio_recording(raw: *mut VM, track: u8) -> u32 {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    let size = vm.mem.audio.encode_recording(track as usize) as u32;
+    mem::forget(vm);
+    return size;
+  }
+}
+
+#[no_mangle]
+pub fn get_audio_recording_pointer(raw: *mut VM) -> *mut u8 {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    let ptr = vm.mem.audio.wav_output.as_mut_ptr();
+    mem::forget(vm);
+    return ptr;
+  }
+}
+
+#[no_mangle]
+pub fn get_palette_pointer(raw: *mut VM) -> *mut u32 {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    let ptr = vm.gpu.palette_ptr();
+    mem::forget(vm);
+    return ptr;
+  }
+}
+
+#[no_mangle]
+pub fn get_palette_file_pointer(raw: *mut VM) -> *mut u8 {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    let ptr = vm.gpu.palette_file_ptr();
+    mem::forget(vm);
+    return ptr;
+  }
+}
+
+#[no_mangle]
+pub fn set_palette_preset(raw: *mut VM, preset: u8) {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    match vm::palette::preset_from_index(preset) {
+      Some(p) => vm.gpu.apply_palette_preset(p),
+      None => (),
+    };
+    mem::forget(vm);
+  }
+}
+
+#[no_mangle]
+pub fn set_palette_color(raw: *mut VM, layer: u8, shade: u8, rgb: u32) {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    match vm::palette::layer_from_index(layer) {
+      Some(l) => vm.gpu.set_palette_color(l, shade, rgb),
+      None => (),
+    };
+    mem::forget(vm);
+  }
+}
+
+// Parses a palette file previously copied to the palette file pointer
+#[no_mangle]
+pub fn load_palette(raw: *mut VM, len: u32) -> u8 {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    let len = (len as usize).min(vm.gpu.palette_file.len());
+    let data = vm.gpu.palette_file[0..len].to_vec();
+    let value = if vm.gpu.load_palette_file(&data).is_ok() { 1 } else { 0 };
+    mem::forget(vm);
+    return value;
+  }
+}
+
+// Plugs the serial port into another emulator over a socket. `addr` points
+// to a host:port string, or a socket path when `unix` is set. Gives up and
+// returns 0 if no peer has connected and shaken hands within a few seconds.
+#[no_mangle]
+pub fn connect_link_socket(raw: *mut VM, addr: *const u8, len: u32, listen: u8, unix: u8) -> u8 {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    let bytes = std::slice::from_raw_parts(addr, len as usize);
+    let value = match std::str::from_utf8(bytes) {
+      Ok(addr) => match vm::netlink::open_link(addr, listen != 0, unix != 0) {
+        Ok(device) => {
+          vm.mem.serial.device = Some(device);
+          1
+        },
+        Err(_) => 0,
+      },
+      Err(_) => 0,
+    };
+    mem::forget(vm);
+    return value;
+  }
+}
+
+// Keeps the bytes the game sends over serial for take_serial_output
+#[no_mangle]
+pub fn enable_serial_capture(raw: *mut VM, flag: u8) {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    vm.mem.serial.capture_output = flag != 0;
+    mem::forget(vm);
+  }
+}
+
+// Moves the bytes sent since the last call to the serial output pointer,
+// returning how many there are
+#[no_mangle]
+pub fn take_serial_output(raw: *mut VM) -> u32 {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    let len = vm.mem.serial.take_output() as u32;
+    mem::forget(vm);
+    return len;
+  }
+}
+
+#[no_mangle]
+pub fn get_serial_output_pointer(raw: *mut VM) -> *mut u8 {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    let ptr = vm.mem.serial.taken_output.as_mut_ptr();
+    mem::forget(vm);
+    return ptr;
+  }
+}
+
+#[no_mangle]
+pub fn attach_printer(raw: *mut VM) {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    vm.mem.serial.device = Some(Box::new(vm::printer::create_printer()));
+    mem::forget(vm);
+  }
+}
+
+// Moves the next finished print to the printed image pointer, returning its
+// height in pixels, or 0 if nothing has been printed
+#[no_mangle]
+pub fn take_printed_image(raw: *mut VM) -> u32 {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    let height = match printer(&mut vm) {
+      Some(p) => p.take_print() as u32,
+      None => 0,
+    };
+    mem::forget(vm);
+    return height;
+  }
+}
+
+// Like take_printed_image, but leaves a grayscale PNG file at the printed
+// image pointer, returning its size in bytes
+#[no_mangle]
+pub fn take_printed_png(raw: *mut VM) -> u32 {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    let size = match printer(&mut vm) {
+      Some(p) => p.take_png() as u32,
+      None => 0,
+    };
+    mem::forget(vm);
+    return size;
+  }
+}
+
+// 160 pixels wide, one grayscale byte per pixel, or a PNG file after
+// take_printed_png
+#[no_mangle]
+pub fn get_printed_image_pointer(raw: *mut VM) -> *mut u8 {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    let ptr = match printer(&mut vm) {
+      Some(p) => p.output.as_mut_ptr(),
+      None => std::ptr::null_mut(),
+    };
+    mem::forget(vm);
+    return ptr;
+  }
+}
+
+fn printer(vm: &mut VM) -> Option<&mut vm::printer::Printer> {
+  return match vm.mem.serial.device {
+    Some(ref mut device) => device.as_any().downcast_mut::<vm::printer::Printer>(),
+    None => None,
+  };
+}
+
 #[no_mangle]
 pub fn read_mem(raw: *mut VM, addr: u16) -> u8 {
   unsafe {
@@ -257,6 +771,24 @@ pub fn set_directions(raw: *mut VM, directions: u8) {
   }
 }
 
+#[no_mangle]
+pub fn set_player_buttons(raw: *mut VM, player: u8, buttons: u8) {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    vm.mem.set_player_buttons(player, buttons & 0xf);
+    mem::forget(vm);
+  }
+}
+
+#[no_mangle]
+pub fn set_player_directions(raw: *mut VM, player: u8, directions: u8) {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    vm.mem.set_player_directions(player, directions & 0xf);
+    mem::forget(vm);
+  }
+}
+
 #[no_mangle]
 pub fn set_mbc(raw: *mut VM, mbc: u8) {
   unsafe {
@@ -266,6 +798,26 @@ pub fn set_mbc(raw: *mut VM, mbc: u8) {
   }
 }
 
+// Call once the ROM has been copied in and the VM reset. When skipping the
+// boot ROM, enables CGB mode for CGB carts.
+#[no_mangle]
+pub fn apply_cart_header(raw: *mut VM) {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    vm.apply_cart_header();
+    mem::forget(vm);
+  }
+}
+
+#[no_mangle]
+pub fn set_vram_access_restricted(raw: *mut VM, flag: u8) {
+  unsafe {
+    let mut vm = Box::from_raw(raw);
+    vm.mem.restrict_vram_access = flag != 0;
+    mem::forget(vm);
+  }
+}
+
 #[no_mangle]
 pub fn is_sram_dirty(raw: *mut VM) -> u8 {
   unsafe {
@@ -1,222 +1,1177 @@
-#[cfg(not(test))]
-extern "C" {
-  fn set_channel_1_freq(f: u32);
-  fn set_channel_1_gain(v: u8);
-  fn set_channel_2_freq(f: u32);
-  fn set_channel_2_gain(v: u8);
-  fn set_channel_4_gain(v: u8);
+use vm::wav;
+
+// The APU is clocked at the CPU's base rate
+const CLOCK_RATE: u32 = 4194304;
+
+const DEFAULT_SAMPLE_RATE: u32 = 44100;
+// Stereo frames kept for the host before the oldest are dropped
+const BUFFER_FRAMES: usize = 8192;
+
+// Waveforms for the NRx1 duty bits: 12.5%, 25%, 50% and 75%
+const DUTY_PATTERNS: [u8; 4] = [0b00000001, 0b10000001, 0b10000111, 0b01111110];
+
+// NRx2 with the top five bits clear turns the DAC off, keeping the channel
+// from playing
+fn dac_enabled(nrx2: u8) -> bool {
+  return nrx2 & 0xf8 > 0;
 }
 
-#[cfg(test)]
-fn set_channel_1_freq(f : u32) {}
-#[cfg(test)]
-fn set_channel_1_gain(v : u8) {}
-#[cfg(test)]
-fn set_channel_2_freq(f : u32) {}
-#[cfg(test)]
-fn set_channel_2_gain(v : u8) {}
-#[cfg(test)]
-fn set_channel_4_gain(v: u8) {}
+// Counts down on the frame sequencer's length steps while enabled, silencing
+// the channel when it runs out
+pub struct LengthCounter {
+  pub enabled: bool,
+  pub counter: u16,
+  max: u16,
+}
 
+impl LengthCounter {
+  pub fn load(&mut self, len: u8) {
+    self.counter = self.max - ((len as u16) & (self.max - 1));
+  }
 
-pub struct SquareChannel {
-  index: u8,
+  // Returns true when the channel should be silenced
+  pub fn clock(&mut self) -> bool {
+    if self.enabled && self.counter > 0 {
+      self.counter -= 1;
+      return self.counter == 0;
+    }
+    return false;
+  }
+
+  // Handles the NRx4 length enable and trigger bits. When the next frame
+  // sequencer step doesn't clock lengths, enabling the counter clocks it once
+  // right away, and a trigger reloading an empty counter loses a step.
+  // Returns true when the channel should be silenced.
+  pub fn write_control(&mut self, enable: bool, trigger: bool, frame_step: u8) -> bool {
+    let extra_clock = frame_step & 1 == 1;
+    let was_enabled = self.enabled;
+    self.enabled = enable;
+    let mut expired = false;
+    if extra_clock && !was_enabled && enable && self.counter > 0 {
+      self.counter -= 1;
+      expired = self.counter == 0 && !trigger;
+    }
+    if trigger && self.counter == 0 {
+      self.counter = if enable && extra_clock { self.max - 1 } else { self.max };
+    }
+    return expired;
+  }
+}
+
+pub struct Envelope {
+  pub volume: u8,
+  increase: bool,
+  // Steps every `period` frame sequencer envelope clocks, 0 stops it
+  period: u8,
+  timer: u8,
+}
+
+impl Envelope {
+  // NRx2 holds the starting volume, the direction and the period
+  pub fn trigger(&mut self, nrx2: u8, frame_step: u8) {
+    self.volume = nrx2 >> 4;
+    self.increase = nrx2 & 0x8 > 0;
+    self.period = nrx2 & 0x7;
+    self.timer = self.period;
+    if frame_step == 7 {
+      // The next step clocks envelopes, which is delayed by one
+      self.timer += 1;
+    }
+  }
+
+  pub fn clock(&mut self) {
+    if self.period == 0 {
+      return;
+    }
+    self.timer -= 1;
+    if self.timer > 0 {
+      return;
+    }
+    self.timer = self.period;
+    if self.increase && self.volume < 0xf {
+      self.volume += 1;
+    } else if !self.increase && self.volume > 0 {
+      self.volume -= 1;
+    }
+  }
+}
 
+pub struct SquareChannel {
+  pub enabled: bool,
+  pub length: LengthCounter,
+  pub envelope: Envelope,
   freq: u32,
-  volume: u8,
-  volume_dir: u8,
-  volume_counter: u8,
-  counter: u8,
-  time: u32,
 
+  // Waveform position, advanced every (2048 - freq) * 4 cycles
+  duty: u8,
+  duty_step: u8,
+  freq_timer: u32,
+
+  // Only channel 1 has a sweep unit, NR10 is never written for channel 2
   shadow_freq: u32,
-  sweep_counter: u8,
-  sweep_time: u8,
-  sweep_dir: u8,
+  sweep_enabled: bool,
+  sweep_timer: u8,
+  sweep_period: u8,
+  sweep_negate: bool,
   sweep_shift: u8,
+  // Set once a decreasing sweep has been calculated since the trigger
+  sweep_negated: bool,
 }
 
 impl SquareChannel {
-  pub fn sweep(&mut self, time: u8, dir: u8, shift: u8) {
+  pub fn set_sweep(&mut self, nr10: u8) {
+    self.sweep_period = (nr10 & 0x70) >> 4;
+    self.sweep_negate = nr10 & 0x8 > 0;
+    self.sweep_shift = nr10 & 0x7;
+    if self.sweep_negated && !self.sweep_negate {
+      // Leaving negate mode after it has been used disables the channel
+      self.enabled = false;
+    }
+  }
+
+  // NRx1 holds the duty in bits 6-7 and the length in bits 0-5
+  pub fn set_duty_length(&mut self, value: u8) {
+    self.duty = value >> 6;
+    self.length.load(value);
+  }
+
+  // Takes effect the next time the frequency timer reloads
+  pub fn set_freq(&mut self, freq: u32) {
+    self.freq = freq & 0x7ff;
+  }
+
+  pub fn set_dac(&mut self, nrx2: u8) {
+    if !dac_enabled(nrx2) {
+      self.enabled = false;
+    }
+  }
+
+  // NRx4 write, after the frequency bits have been applied
+  pub fn control(&mut self, value: u8, nrx2: u8, frame_step: u8) {
+    let trigger = value & 0x80 > 0;
+    if self.length.write_control(value & 0x40 > 0, trigger, frame_step) {
+      self.enabled = false;
+    }
+    if trigger {
+      self.trigger(nrx2, frame_step);
+    }
+  }
+
+  fn trigger(&mut self, nrx2: u8, frame_step: u8) {
+    self.envelope.trigger(nrx2, frame_step);
+    self.freq_timer = (2048 - self.freq) * 4;
+    self.enabled = dac_enabled(nrx2);
+
     self.shadow_freq = self.freq;
-    self.sweep_counter = 0;
-    self.sweep_time = time;
-    self.sweep_dir = dir;
-    self.sweep_shift = shift;
-  }
-
-  pub fn reset(&mut self, len: u8, freq: u32, vol: u8, vol_dir: u8, vol_len: u8) {
-    self.counter = 64 - (len & 0x3f);
-    self.freq = freq;
-    self.time = 0;
-    self.volume = vol;
-    self.volume_dir = vol_dir;
-    self.volume_counter = vol_len;
-    let f = 131072 / (2048 - freq);
-    match self.index {
-      0 => unsafe { set_channel_1_freq(f); set_channel_1_gain(vol); },
-      1 => unsafe { set_channel_2_freq(f); set_channel_2_gain(vol); },
-      _ => (),
+    self.sweep_timer = if self.sweep_period > 0 { self.sweep_period } else { 8 };
+    self.sweep_enabled = self.sweep_period > 0 || self.sweep_shift > 0;
+    self.sweep_negated = false;
+    if self.sweep_shift > 0 {
+      // Overflow is checked right away, without updating the frequency
+      self.next_sweep_freq();
+    }
+  }
+
+  // Calculates the swept frequency, silencing the channel when it overflows
+  fn next_sweep_freq(&mut self) -> u32 {
+    let delta = self.shadow_freq >> self.sweep_shift;
+    let freq = if self.sweep_negate {
+      self.sweep_negated = true;
+      self.shadow_freq - delta
+    } else {
+      self.shadow_freq + delta
     };
+    if freq > 2047 {
+      self.enabled = false;
+    }
+    return freq;
+  }
+
+  pub fn clock_length(&mut self) {
+    if self.length.clock() {
+      self.enabled = false;
+    }
+  }
+
+  pub fn clock_sweep(&mut self) {
+    if !self.sweep_enabled {
+      return;
+    }
+    self.sweep_timer -= 1;
+    if self.sweep_timer > 0 {
+      return;
+    }
+    self.sweep_timer = if self.sweep_period > 0 { self.sweep_period } else { 8 };
+    if self.sweep_period == 0 {
+      return;
+    }
+    let freq = self.next_sweep_freq();
+    if freq <= 2047 && self.sweep_shift > 0 {
+      self.shadow_freq = freq;
+      self.freq = freq;
+      self.next_sweep_freq();
+    }
   }
 
   pub fn add_time(&mut self, t: u32) {
-    let (next_time, overflow) = self.time.overflowing_add(t);
-    if self.counter > 0 {
-      // 256 Hz
-      if overflow || ((next_time / 65536) > (self.time / 65536)) {
-        self.counter -= 1;
-        if self.counter <= 0 {
-          self.counter = 0;
-          match self.index {
-            0 => unsafe { set_channel_1_gain(0); },
-            1 => unsafe { set_channel_2_gain(0); },
-            _ => (),
-          };
-        }
-      }
+    let mut remaining = t;
+    while remaining >= self.freq_timer {
+      remaining -= self.freq_timer;
+      self.freq_timer = (2048 - self.freq) * 4;
+      self.duty_step = (self.duty_step + 1) & 0x7;
     }
+    self.freq_timer -= remaining;
+  }
 
-    if self.sweep_time > 0 && self.shadow_freq > 0 {
-      // 128 Hz
-      if overflow || ((next_time / 131072) > (self.time / 131072)) {
-        let next_counter = self.sweep_counter + 1;
-        if (next_counter / self.sweep_time) > (self.sweep_counter / self.sweep_time) {
-          let delta = self.shadow_freq >> self.sweep_shift;
-          if self.sweep_dir == 0 {
-            self.shadow_freq -= delta;
-          } else {
-            self.shadow_freq += delta;
-            if self.shadow_freq > 2047 {
-              self.shadow_freq = 0;
-            }
-          }
-          let f = 131072 / (2048 - self.shadow_freq);
-          unsafe { set_channel_1_freq(f); }
-        }
-        self.sweep_counter = next_counter;
-      }
+  // Current output level, 0 to 15
+  pub fn output(&self) -> u8 {
+    if !self.enabled {
+      return 0;
     }
+    let high = (DUTY_PATTERNS[self.duty as usize] >> (7 - self.duty_step)) & 1;
+    return if high > 0 { self.envelope.volume } else { 0 };
+  }
+}
 
-    if self.volume_counter > 0 {
-      // 64 Hz
-      if overflow || ((next_time / 262272) > (self.time / 262272)) {
-        self.volume_counter -= 1;
-        if self.volume_dir == 0 && self.volume > 0 {
-          self.volume -= 1;
-        } else if self.volume_dir == 1 && self.volume < 0xf {
-          self.volume += 1;
-        }
-        match self.index {
-          0 => unsafe { set_channel_1_gain(self.volume); },
-          1 => unsafe { set_channel_2_gain(self.volume); },
-          _ => (),
-        };
-      }
+pub struct WaveChannel {
+  pub enabled: bool,
+  // NR30 bit 7, the channel can't play while its DAC is off
+  pub dac_enabled: bool,
+  pub length: LengthCounter,
+  // NR32 output level: mute, 100%, 50% or 25%
+  volume_code: u8,
+  freq: u32,
+
+  // 32 4-bit samples, high nibble first
+  pub ram: [u8; 16],
+  position: u8,
+  // Byte most recently fetched from wave RAM, the one being played
+  sample_buffer: u8,
+  freq_timer: u32,
+  // Cycles since the last wave RAM fetch
+  since_fetch: u32,
+}
+
+impl WaveChannel {
+  pub fn set_dac(&mut self, on: bool) {
+    self.dac_enabled = on;
+    if !on {
+      self.enabled = false;
+    }
+  }
+
+  pub fn set_volume_code(&mut self, code: u8) {
+    self.volume_code = code & 0x3;
+  }
+
+  // Takes effect the next time the frequency timer reloads
+  pub fn set_freq(&mut self, freq: u32) {
+    self.freq = freq & 0x7ff;
+  }
+
+  // NR34 write, after the frequency bits have been applied
+  pub fn control(&mut self, value: u8, frame_step: u8) {
+    let trigger = value & 0x80 > 0;
+    if self.length.write_control(value & 0x40 > 0, trigger, frame_step) {
+      self.enabled = false;
+    }
+    if trigger {
+      self.trigger();
+    }
+  }
+
+  fn trigger(&mut self) {
+    self.position = 0;
+    // The first sample is fetched after a short delay
+    self.freq_timer = (2048 - self.freq) * 2 + 6;
+    self.since_fetch = 0xffff;
+    self.enabled = self.dac_enabled;
+  }
+
+  pub fn clock_length(&mut self) {
+    if self.length.clock() {
+      self.enabled = false;
+    }
+  }
+
+  pub fn add_time(&mut self, t: u32) {
+    if !self.enabled {
+      return;
+    }
+    let mut remaining = t;
+    self.since_fetch = self.since_fetch.saturating_add(t);
+    while remaining >= self.freq_timer {
+      remaining -= self.freq_timer;
+      self.freq_timer = (2048 - self.freq) * 2;
+      self.position = (self.position + 1) & 0x1f;
+      self.sample_buffer = self.ram[(self.position >> 1) as usize];
+      self.since_fetch = remaining;
+    }
+    self.freq_timer -= remaining;
+  }
+
+  pub fn output(&self) -> u8 {
+    if !self.enabled || self.volume_code == 0 {
+      return 0;
+    }
+    let sample = if self.position & 1 == 0 {
+      self.sample_buffer >> 4
+    } else {
+      self.sample_buffer & 0xf
+    };
+    return sample >> (self.volume_code - 1);
+  }
+
+  // While playing, wave RAM accesses go to the byte being played instead. The
+  // DMG only allows this on the cycle the APU fetches it, reading 0xff and
+  // dropping writes otherwise.
+  fn ram_index(&self, index: usize, cgb: bool) -> Option<usize> {
+    if !self.enabled {
+      return Some(index);
+    }
+    if cgb || self.since_fetch < 4 {
+      return Some((self.position >> 1) as usize);
+    }
+    return None;
+  }
+
+  pub fn read_ram(&self, index: usize, cgb: bool) -> u8 {
+    return match self.ram_index(index, cgb) {
+      Some(i) => self.ram[i],
+      None => 0xff,
+    };
+  }
+
+  pub fn write_ram(&mut self, index: usize, value: u8, cgb: bool) {
+    if let Some(i) = self.ram_index(index, cgb) {
+      self.ram[i] = value;
     }
-    self.time = next_time;
   }
 }
 
+// Divisors selected by NR43 bits 0-2, shifted left by the clock shift
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
 pub struct NoiseChannel {
-  volume: u8,
-  volume_dir: u8,
-  volume_counter: u8,
-  counter: u8,
-  time: u32,
+  pub enabled: bool,
+  pub length: LengthCounter,
+  pub envelope: Envelope,
+
+  lfsr: u16,
+  // NR43 bit 3, also feeds bit 6 back for a shorter, more tonal sequence
+  short_mode: bool,
+  clock_shift: u8,
+  divisor_code: u8,
+  freq_timer: u32,
 }
 
 impl NoiseChannel {
-  pub fn reset(&mut self, len: u8, vol: u8, vol_dir: u8, vol_len: u8) {
-    self.counter = 64 - (len & 0x3f);
-    self.time = 0;
-    self.volume = vol;
-    self.volume_dir = vol_dir;
-    self.volume_counter = vol_len;
-    unsafe { set_channel_4_gain(vol); }
+  pub fn set_polynomial(&mut self, value: u8) {
+    self.clock_shift = value >> 4;
+    self.short_mode = value & 0x8 > 0;
+    self.divisor_code = value & 0x7;
+  }
+
+  fn period(&self) -> u32 {
+    return NOISE_DIVISORS[self.divisor_code as usize] << self.clock_shift;
+  }
+
+  pub fn set_dac(&mut self, nr42: u8) {
+    if !dac_enabled(nr42) {
+      self.enabled = false;
+    }
+  }
+
+  // NR44 write
+  pub fn control(&mut self, value: u8, nr42: u8, frame_step: u8) {
+    let trigger = value & 0x80 > 0;
+    if self.length.write_control(value & 0x40 > 0, trigger, frame_step) {
+      self.enabled = false;
+    }
+    if trigger {
+      self.trigger(nr42, frame_step);
+    }
+  }
+
+  fn trigger(&mut self, nr42: u8, frame_step: u8) {
+    self.envelope.trigger(nr42, frame_step);
+    self.lfsr = 0x7fff;
+    self.freq_timer = self.period();
+    self.enabled = dac_enabled(nr42);
+  }
+
+  pub fn clock_length(&mut self) {
+    if self.length.clock() {
+      self.enabled = false;
+    }
   }
 
   pub fn add_time(&mut self, t: u32) {
-    let (next_time, overflow) = self.time.overflowing_add(t);
-    if self.counter > 0 {
-      // 256 Hz
-      if overflow || ((next_time / 65536) > (self.time / 65536)) {
-        self.counter -= 1;
-        if self.counter <= 0 {
-          self.counter = 0;
-          self.volume_counter = 0;
-          unsafe { set_channel_4_gain(0); }
-        }
-      }
+    if !self.enabled {
+      return;
+    }
+    let mut remaining = t;
+    while remaining >= self.freq_timer {
+      remaining -= self.freq_timer;
+      self.freq_timer = self.period();
+      self.clock_lfsr();
     }
+    self.freq_timer -= remaining;
+  }
 
-    if self.volume_counter > 0 {
-      // 64 Hz
-      if overflow || ((next_time / 262272) > (self.time / 262272)) {
-        self.volume_counter -= 1;
-        if self.volume_dir == 0 && self.volume > 0 {
-          self.volume -= 1;
-        } else if self.volume_dir == 1 && self.volume < 0xf {
-          self.volume += 1;
-        }
-        unsafe { set_channel_4_gain(self.volume); }
-      }
+  fn clock_lfsr(&mut self) {
+    let bit = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+    self.lfsr = (self.lfsr >> 1) | (bit << 14);
+    if self.short_mode {
+      self.lfsr = (self.lfsr & !0x40) | (bit << 6);
     }
-    self.time = next_time;
+  }
+
+  pub fn output(&self) -> u8 {
+    if !self.enabled {
+      return 0;
+    }
+    return if self.lfsr & 1 == 0 { self.envelope.volume } else { 0 };
+  }
+}
+
+// Frames mixed between starting and stopping a recording
+pub struct Recording {
+  pub sample_rate: u32,
+  // Interleaved stereo, as heard
+  pub mix: Vec<f32>,
+  // Each channel on its own, these sum to the mix
+  pub stems: Option<[Vec<f32>; 4]>,
+}
+
+impl Recording {
+  pub fn frames(&self) -> usize {
+    return self.mix.len() / 2;
+  }
+
+  pub fn to_wav(&self) -> Vec<u8> {
+    return wav::encode_pcm16(self.sample_rate, 2, &self.mix);
+  }
+
+  // Channel 1-4 alone, when stems were recorded
+  pub fn stem_to_wav(&self, channel: usize) -> Option<Vec<u8>> {
+    return match self.stems {
+      Some(ref stems) if channel >= 1 && channel <= 4 => {
+        Some(wav::encode_pcm16(self.sample_rate, 2, &stems[channel - 1]))
+      },
+      _ => None,
+    };
   }
 }
 
 pub struct Audio {
   pub channel_1: SquareChannel,
   pub channel_2: SquareChannel,
+  pub channel_3: WaveChannel,
   pub channel_4: NoiseChannel,
+
+  // NR52 bit 7
+  pub enabled: bool,
+  // Next step of the 512 Hz frame sequencer
+  pub frame_step: u8,
+  // NR50 volumes, 0 to 7
+  pub master_left: u8,
+  pub master_right: u8,
+  // NR50 bits 7 and 3, mixing in cartridge audio which isn't emulated
+  pub vin_left: bool,
+  pub vin_right: bool,
+  // NR51, bits 4-7 send channels 1-4 left and bits 0-3 send them right
+  pub panning: u8,
+
+  // Debugging controls for channels 1-4, applied in the mixer only so games
+  // never see them
+  muted: [bool; 4],
+  solo: [bool; 4],
+  gain: [f32; 4],
+
+  sample_rate: u32,
+  // Cycles multiplied by the sample rate, a sample is due every CLOCK_RATE
+  sample_time: u32,
+
+  // Interleaved stereo samples waiting for the host
+  buffer: Vec<f32>,
+  read_index: usize,
+  write_index: usize,
+  // Contiguous copy of the drained samples, read through a pointer
+  pub output: Vec<f32>,
+
+  // Kept after recording stops, until taken or a new one starts
+  pub recording: Option<Recording>,
+  recording_active: bool,
+  // Encoded WAV file, read through a pointer
+  pub wav_output: Vec<u8>,
 }
 
 impl Audio {
+  // Called on each falling edge of DIV bit 4, or bit 5 in double speed.
+  // Lengths are clocked on even steps, sweep on 2 and 6, envelopes on 7.
+  pub fn clock_frame_sequencer(&mut self) {
+    if !self.enabled {
+      return;
+    }
+    let step = self.frame_step;
+    if step & 1 == 0 {
+      self.channel_1.clock_length();
+      self.channel_2.clock_length();
+      self.channel_3.clock_length();
+      self.channel_4.clock_length();
+    }
+    if step == 2 || step == 6 {
+      self.channel_1.clock_sweep();
+    }
+    if step == 7 {
+      self.channel_1.envelope.clock();
+      self.channel_2.envelope.clock();
+      self.channel_4.envelope.clock();
+    }
+    self.frame_step = (step + 1) & 0x7;
+  }
+
   pub fn add_time(&mut self, t: u8) {
     self.channel_1.add_time(t as u32);
     self.channel_2.add_time(t as u32);
+    self.channel_3.add_time(t as u32);
     self.channel_4.add_time(t as u32);
+
+    self.sample_time += (t as u32) * self.sample_rate;
+    while self.sample_time >= CLOCK_RATE {
+      self.sample_time -= CLOCK_RATE;
+      let channels = self.mix_channels();
+      let mut left = 0.0;
+      let mut right = 0.0;
+      for &(l, r) in channels.iter() {
+        left += l;
+        right += r;
+      }
+      self.push_frame(left, right);
+      if self.recording_active {
+        self.record_frame(left, right, &channels);
+      }
+    }
+  }
+
+  // Each channel's contribution to the left and right outputs
+  fn mix_channels(&self) -> [(f32, f32); 4] {
+    let mut channels = [(0.0, 0.0); 4];
+    if !self.enabled {
+      return channels;
+    }
+    let levels = [
+      self.channel_1.output(),
+      self.channel_2.output(),
+      self.channel_3.output(),
+      self.channel_4.output(),
+    ];
+    let any_solo = self.solo.iter().any(|s| *s);
+    // Leave headroom for all four channels
+    let left_gain = ((self.master_left + 1) as f32) / 8.0 / 4.0;
+    let right_gain = ((self.master_right + 1) as f32) / 8.0 / 4.0;
+    for (i, level) in levels.iter().enumerate() {
+      if self.muted[i] || (any_solo && !self.solo[i]) {
+        continue;
+      }
+      let sample = (*level as f32) / 15.0 * self.gain[i];
+      if self.panning & (0x10 << i) > 0 {
+        channels[i].0 = sample * left_gain;
+      }
+      if self.panning & (0x1 << i) > 0 {
+        channels[i].1 = sample * right_gain;
+      }
+    }
+    return channels;
+  }
+
+  pub fn set_channel_muted(&mut self, channel: usize, muted: bool) {
+    if channel >= 1 && channel <= 4 {
+      self.muted[channel - 1] = muted;
+    }
+  }
+
+  // While any channel is soloed, only soloed channels are heard
+  pub fn set_channel_solo(&mut self, channel: usize, solo: bool) {
+    if channel >= 1 && channel <= 4 {
+      self.solo[channel - 1] = solo;
+    }
+  }
+
+  pub fn set_channel_gain(&mut self, channel: usize, gain: f32) {
+    if channel >= 1 && channel <= 4 && gain >= 0.0 {
+      self.gain[channel - 1] = gain;
+    }
+  }
+
+  // Recording starts with the next mixed frame, so it lines up exactly with
+  // the cycles emulated from here on
+  pub fn start_recording(&mut self, stems: bool) {
+    self.recording = Some(Recording {
+      sample_rate: self.sample_rate,
+      mix: Vec::new(),
+      stems: if stems { Some([Vec::new(), Vec::new(), Vec::new(), Vec::new()]) } else { None },
+    });
+    self.recording_active = true;
+  }
+
+  // Returns the number of frames recorded
+  pub fn stop_recording(&mut self) -> usize {
+    self.recording_active = false;
+    return match self.recording {
+      Some(ref recording) => recording.frames(),
+      None => 0,
+    };
+  }
+
+  fn record_frame(&mut self, left: f32, right: f32, channels: &[(f32, f32); 4]) {
+    if let Some(ref mut recording) = self.recording {
+      recording.mix.push(left);
+      recording.mix.push(right);
+      if let Some(ref mut stems) = recording.stems {
+        for i in 0..4 {
+          stems[i].push(channels[i].0);
+          stems[i].push(channels[i].1);
+        }
+      }
+    }
+  }
+
+  // Encodes the mix, or channel 1-4 alone, to `wav_output`, returning its size
+  // in bytes, or 0 when there is nothing to encode
+  pub fn encode_recording(&mut self, track: usize) -> usize {
+    let wav = match self.recording {
+      Some(ref recording) if track == 0 => Some(recording.to_wav()),
+      Some(ref recording) => recording.stem_to_wav(track),
+      None => None,
+    };
+    self.wav_output = wav.unwrap_or(Vec::new());
+    return self.wav_output.len();
+  }
+
+  // NR52 bits 0-3
+  pub fn channel_status(&self) -> u8 {
+    let mut status = 0;
+    if self.channel_1.enabled {
+      status |= 1;
+    }
+    if self.channel_2.enabled {
+      status |= 2;
+    }
+    if self.channel_3.enabled {
+      status |= 4;
+    }
+    if self.channel_4.enabled {
+      status |= 8;
+    }
+    return status;
+  }
+
+  // Powering the APU off resets every channel, leaving wave RAM alone. The
+  // DMG also keeps its length counters, which stay writable while off.
+  pub fn power_off(&mut self, cgb: bool) {
+    let ram = self.channel_3.ram;
+    let lengths = [
+      self.channel_1.length.counter,
+      self.channel_2.length.counter,
+      self.channel_3.length.counter,
+      self.channel_4.length.counter,
+    ];
+    self.channel_1 = create_square_channel();
+    self.channel_2 = create_square_channel();
+    self.channel_3 = create_wave_channel();
+    self.channel_4 = create_noise_channel();
+    self.channel_3.ram = ram;
+    if !cgb {
+      self.channel_1.length.counter = lengths[0];
+      self.channel_2.length.counter = lengths[1];
+      self.channel_3.length.counter = lengths[2];
+      self.channel_4.length.counter = lengths[3];
+    }
+    self.enabled = false;
+    self.frame_step = 0;
+    self.master_left = 0;
+    self.master_right = 0;
+    self.vin_left = false;
+    self.vin_right = false;
+    self.panning = 0;
+  }
+
+  fn push_frame(&mut self, left: f32, right: f32) {
+    let capacity = self.buffer.len();
+    self.buffer[self.write_index] = left;
+    self.buffer[self.write_index + 1] = right;
+    self.write_index = (self.write_index + 2) % capacity;
+    if self.write_index == self.read_index {
+      // Full, drop the oldest frame
+      self.read_index = (self.read_index + 2) % capacity;
+    }
+  }
+
+  pub fn set_sample_rate(&mut self, rate: u32) {
+    if rate > 0 && !self.recording_active {
+      self.sample_rate = rate;
+      self.sample_time = 0;
+    }
+  }
+
+  pub fn available_frames(&self) -> usize {
+    let capacity = self.buffer.len();
+    return ((self.write_index + capacity - self.read_index) % capacity) / 2;
+  }
+
+  // Moves every waiting frame to `output`, returning how many there were
+  pub fn drain(&mut self) -> usize {
+    let frames = self.available_frames();
+    self.output.clear();
+    let capacity = self.buffer.len();
+    for _ in 0..frames * 2 {
+      self.output.push(self.buffer[self.read_index]);
+      self.read_index = (self.read_index + 1) % capacity;
+    }
+    return frames;
+  }
+
+  pub fn output_ptr(&mut self) -> *mut f32 {
+    return self.output.as_mut_ptr();
   }
 }
 
+fn create_length_counter(max: u16) -> LengthCounter {
+  return LengthCounter {
+    enabled: false,
+    counter: 0,
+    max: max,
+  };
+}
+
+fn create_envelope() -> Envelope {
+  return Envelope {
+    volume: 0,
+    increase: false,
+    period: 0,
+    timer: 0,
+  };
+}
+
+fn create_square_channel() -> SquareChannel {
+  return SquareChannel {
+    enabled: false,
+    length: create_length_counter(64),
+    envelope: create_envelope(),
+    freq: 0,
+    duty: 0,
+    duty_step: 0,
+    freq_timer: 8192,
+    shadow_freq: 0,
+    sweep_enabled: false,
+    sweep_timer: 0,
+    sweep_period: 0,
+    sweep_negate: false,
+    sweep_shift: 0,
+    sweep_negated: false,
+  };
+}
+
+fn create_wave_channel() -> WaveChannel {
+  return WaveChannel {
+    enabled: false,
+    dac_enabled: false,
+    length: create_length_counter(256),
+    volume_code: 0,
+    freq: 0,
+    ram: [0; 16],
+    position: 0,
+    sample_buffer: 0,
+    freq_timer: 4096,
+    since_fetch: 0xffff,
+  };
+}
+
+fn create_noise_channel() -> NoiseChannel {
+  return NoiseChannel {
+    enabled: false,
+    length: create_length_counter(64),
+    envelope: create_envelope(),
+    lfsr: 0x7fff,
+    short_mode: false,
+    clock_shift: 0,
+    divisor_code: 0,
+    freq_timer: 8,
+  };
+}
+
 pub fn create_audio() -> Audio {
   return Audio {
-    channel_1: SquareChannel {
-      index: 0,
-      volume: 0,
-      volume_dir: 0,
-      volume_counter: 0,
-      counter: 0,
-      time: 0,
-      freq: 0,
-      shadow_freq: 0,
-      sweep_counter: 0,
-      sweep_time: 0,
-      sweep_dir: 0,
-      sweep_shift: 0,
-    },
-    channel_2: SquareChannel {
-      index: 1,
-      volume: 0,
-      volume_dir: 0,
-      volume_counter: 0,
-      counter: 0,
-      time: 0,
-      freq: 0,
-      shadow_freq: 0,
-      sweep_counter: 0,
-      sweep_time: 0,
-      sweep_dir: 0,
-      sweep_shift: 0,
-    },
-
-    channel_4: NoiseChannel {
-      volume: 0,
-      volume_dir: 0,
-      volume_counter: 0,
-      counter: 0,
-      time: 0,
-    },
+    channel_1: create_square_channel(),
+    channel_2: create_square_channel(),
+
+    channel_3: create_wave_channel(),
+    channel_4: create_noise_channel(),
+
+    enabled: false,
+    frame_step: 0,
+    master_left: 7,
+    master_right: 7,
+    vin_left: false,
+    vin_right: false,
+    panning: 0xff,
+
+    muted: [false; 4],
+    solo: [false; 4],
+    gain: [1.0; 4],
+
+    sample_rate: DEFAULT_SAMPLE_RATE,
+    sample_time: 0,
+
+    buffer: vec![0.0; BUFFER_FRAMES * 2],
+    read_index: 0,
+    write_index: 0,
+    output: Vec::with_capacity(BUFFER_FRAMES * 2),
+
+    recording: None,
+    recording_active: false,
+    wav_output: Vec::new(),
   };
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+  use vm::audio::create_audio;
+
+  #[test]
+  fn synthesizes_square_wave() {
+    let mut audio = create_audio();
+    audio.enabled = true;
+    audio.set_sample_rate(4194304 / 64);
+    // 1024 Hz: the waveform steps every 512 cycles, 50% duty is high on steps
+    // 0, 5, 6 and 7
+    audio.channel_1.set_duty_length(0x80);
+    audio.channel_1.set_freq(1920);
+    audio.channel_1.control(0x80, 0xf0, 0);
+    for _ in 0..(4096 / 16) {
+      audio.add_time(16);
+    }
+    assert_eq!(audio.drain(), 64);
+    let left: Vec<f32> = audio.output.iter().step_by(2).cloned().collect();
+    let high = 0.25;
+    assert_eq!(left[0], high);
+    assert_eq!(left[6], high);
+    assert_eq!(left[7], 0.0);
+    assert_eq!(left[38], 0.0);
+    assert_eq!(left[39], high);
+    assert_eq!(left[63], high);
+    assert_eq!(audio.drain(), 0);
+  }
+
+  #[test]
+  fn square_duty_cycles() {
+    let mut audio = create_audio();
+    let square = &mut audio.channel_2;
+    square.set_freq(2047);
+    let expected = [[0, 0, 0, 0, 0, 0, 0, 1], [1, 0, 0, 0, 0, 0, 0, 1], [1, 0, 0, 0, 0, 1, 1, 1], [0, 1, 1, 1, 1, 1, 1, 0]];
+    for duty in 0..4 {
+      square.set_duty_length((duty as u8) << 6);
+      square.control(0x80, 0x10, 0);
+      let mut wave = [0; 8];
+      for i in 0..8 {
+        wave[i] = square.output();
+        square.add_time(4);
+      }
+      assert_eq!(wave, expected[duty]);
+    }
+  }
+
+  #[test]
+  fn square_sweep() {
+    let mut audio = create_audio();
+    audio.enabled = true;
+    // Sweep up by freq >> 1 on every sweep clock
+    audio.channel_1.set_sweep(0x11);
+    audio.channel_1.set_freq(0x400);
+    audio.channel_1.control(0x80, 0xf0, 0);
+    assert!(audio.channel_1.enabled);
+    audio.clock_frame_sequencer();
+    audio.clock_frame_sequencer();
+    assert_eq!(audio.channel_1.freq, 0x400);
+    audio.clock_frame_sequencer();
+    assert_eq!(audio.channel_1.freq, 0x600);
+    // 0x600 + 0x300 would overflow, which is caught right after the update
+    assert!(!audio.channel_1.enabled);
+
+    // The overflow check also happens on trigger
+    audio.channel_1.set_freq(0x700);
+    audio.channel_1.control(0x80, 0xf0, 0);
+    assert!(!audio.channel_1.enabled);
+
+    // Leaving negate mode after a decreasing calculation disables the channel
+    audio.channel_1.set_sweep(0x19);
+    audio.channel_1.set_freq(0x400);
+    audio.channel_1.control(0x80, 0xf0, 0);
+    assert!(audio.channel_1.enabled);
+    audio.channel_1.set_sweep(0x11);
+    assert!(!audio.channel_1.enabled);
+  }
+
+  #[test]
+  fn length_counter() {
+    let mut audio = create_audio();
+    audio.enabled = true;
+    audio.channel_2.set_duty_length(62);
+    audio.channel_2.control(0x80, 0xf0, 0);
+    // Lengths only count down while enabled
+    audio.clock_frame_sequencer();
+    assert_eq!(audio.channel_2.length.counter, 2);
+
+    // The next step doesn't clock lengths, so enabling clocks it right away
+    audio.channel_2.control(0x40, 0xf0, audio.frame_step);
+    assert_eq!(audio.channel_2.length.counter, 1);
+    assert!(audio.channel_2.enabled);
+    audio.clock_frame_sequencer();
+    audio.clock_frame_sequencer();
+    assert!(!audio.channel_2.enabled);
+
+    // A trigger reloading the empty counter loses that step
+    audio.channel_2.control(0xc0, 0xf0, audio.frame_step);
+    assert_eq!(audio.channel_2.length.counter, 63);
+    assert!(audio.channel_2.enabled);
+
+    // Clocking to zero without a trigger disables the channel
+    audio.channel_2.set_duty_length(63);
+    audio.channel_2.control(0x00, 0xf0, audio.frame_step);
+    audio.channel_2.control(0x40, 0xf0, audio.frame_step);
+    assert_eq!(audio.channel_2.length.counter, 0);
+    assert!(!audio.channel_2.enabled);
+
+    // The wave channel counts from 256
+    audio.channel_3.set_dac(true);
+    audio.channel_3.control(0x80, 0);
+    assert_eq!(audio.channel_3.length.counter, 256);
+  }
+
+  #[test]
+  fn plays_wave_ram() {
+    let mut audio = create_audio();
+    let wave = &mut audio.channel_3;
+    for i in 0..16 {
+      wave.ram[i] = ((i as u8 * 2) << 4) | (i as u8 * 2 + 1);
+    }
+    wave.set_dac(true);
+    wave.set_volume_code(1);
+    // Each sample lasts (2048 - 2040) * 2 = 16 cycles
+    wave.set_freq(2040);
+    wave.control(0x80, 0);
+    wave.add_time(6);
+    let mut samples = vec![];
+    for _ in 0..32 {
+      wave.add_time(16);
+      samples.push(wave.output());
+    }
+    assert_eq!(samples[0], 1);
+    assert_eq!(samples[14], 15);
+    assert_eq!(samples[31], 0);
+
+    wave.set_volume_code(3);
+    assert_eq!(wave.output(), 0);
+    wave.add_time(16 * 15);
+    assert_eq!(wave.output(), 15 >> 2);
+
+    wave.set_dac(false);
+    assert!(!wave.enabled);
+    assert_eq!(wave.output(), 0);
+  }
+
+  #[test]
+  fn wave_ram_access_while_playing() {
+    let mut audio = create_audio();
+    let wave = &mut audio.channel_3;
+    wave.ram[3] = 0x42;
+    wave.set_dac(true);
+    wave.set_freq(2040);
+    wave.control(0x80, 0);
+    assert_eq!(wave.read_ram(0, false), 0xff);
+    // Right as the first sample is fetched from byte 0
+    wave.add_time(22);
+    wave.write_ram(5, 0x99, false);
+    assert_eq!(wave.ram[0], 0x99);
+    assert_eq!(wave.ram[5], 0);
+    wave.add_time(8);
+    assert_eq!(wave.read_ram(0, false), 0xff);
+    // The CGB always sees the current byte
+    assert_eq!(wave.read_ram(3, true), 0x99);
+
+    wave.set_dac(false);
+    assert_eq!(wave.read_ram(3, false), 0x42);
+  }
+
+  #[test]
+  fn noise_lfsr_sequence() {
+    let mut audio = create_audio();
+    let noise = &mut audio.channel_4;
+    noise.set_polynomial(0x00);
+    noise.control(0x80, 0xf0, 0);
+    let mut lfsr = vec![];
+    for _ in 0..4 {
+      noise.add_time(8);
+      lfsr.push(noise.lfsr);
+    }
+    assert_eq!(lfsr, vec![0x3fff, 0x1fff, 0x0fff, 0x07ff]);
+    // Ones keep shifting out until the first zero reaches bit 0
+    assert_eq!(noise.output(), 0);
+    for _ in 0..11 {
+      noise.add_time(8);
+    }
+    assert_eq!(noise.lfsr, 0x4000);
+    assert_eq!(noise.output(), 15);
+
+    // The full sequence repeats every 32767 clocks
+    noise.control(0x80, 0xf0, 0);
+    noise.add_time(8 * 32767);
+    assert_eq!(noise.lfsr, 0x7fff);
+
+    // 7-bit mode repeats every 127
+    noise.set_polynomial(0x08);
+    noise.control(0x80, 0xf0, 0);
+    noise.add_time(8);
+    let start = noise.lfsr & 0x7f;
+    noise.add_time(8 * 127);
+    assert_eq!(noise.lfsr & 0x7f, start);
+  }
+
+  #[test]
+  fn noise_clock_and_envelope() {
+    let mut audio = create_audio();
+    audio.enabled = true;
+    // Divisor 16 shifted by 2: one LFSR clock every 64 cycles
+    audio.channel_4.set_polynomial(0x21);
+    audio.channel_4.control(0x80, 0xf1, 0);
+    audio.channel_4.add_time(63);
+    assert_eq!(audio.channel_4.lfsr, 0x7fff);
+    audio.channel_4.add_time(1);
+    assert_eq!(audio.channel_4.lfsr, 0x3fff);
+
+    // The volume falls one step on every envelope clock
+    assert_eq!(audio.channel_4.envelope.volume, 15);
+    for _ in 0..8 {
+      audio.clock_frame_sequencer();
+    }
+    assert_eq!(audio.channel_4.envelope.volume, 14);
+
+    // A trigger just before the envelope step delays it
+    for _ in 0..7 {
+      audio.clock_frame_sequencer();
+    }
+    audio.channel_4.control(0x80, 0xf1, audio.frame_step);
+    audio.clock_frame_sequencer();
+    assert_eq!(audio.channel_4.envelope.volume, 15);
+
+    // A DAC without volume or increasing envelope keeps the channel off
+    audio.channel_4.control(0x80, 0x00, 0);
+    assert!(!audio.channel_4.enabled);
+    audio.channel_4.control(0x80, 0x08, 0);
+    assert!(audio.channel_4.enabled);
+  }
+
+  #[test]
+  fn pans_channels() {
+    let mut audio = create_audio();
+    audio.enabled = true;
+    audio.set_sample_rate(4194304 / 4);
+    audio.channel_2.set_duty_length(0xc0);
+    audio.channel_2.set_freq(1024);
+    audio.channel_2.control(0x80, 0xf0, 0);
+    // 75% duty is high on step 1
+    for _ in 0..(4096 / 16) {
+      audio.add_time(16);
+    }
+    audio.drain();
+    audio.panning = 0x20;
+    audio.add_time(4);
+    audio.panning = 0x02;
+    audio.add_time(4);
+    audio.panning = 0x00;
+    audio.add_time(4);
+    assert_eq!(audio.drain(), 3);
+    assert_eq!(audio.output, vec![0.25, 0.0, 0.0, 0.25, 0.0, 0.0]);
+  }
+
+  #[test]
+  fn records_mix_and_stems() {
+    let mut audio = create_audio();
+    audio.enabled = true;
+    audio.set_sample_rate(4194304 / 32);
+    audio.channel_1.set_freq(2000);
+    audio.channel_1.control(0x80, 0xf0, 0);
+    audio.channel_4.control(0x80, 0x80, 0);
+    audio.add_time(16);
+    audio.start_recording(true);
+    // Frames are due every 32 cycles, the first one 16 cycles from now
+    for _ in 0..1000 {
+      audio.add_time(4);
+    }
+    assert_eq!(audio.stop_recording(), 125);
+    audio.add_time(64);
+
+    let recording = audio.recording.take().unwrap();
+    assert_eq!(recording.frames(), 125);
+    let stems = recording.stems.as_ref().unwrap();
+    assert!(stems[0].iter().any(|s| *s > 0.0));
+    assert!(stems[1].iter().all(|s| *s == 0.0));
+    for i in 0..recording.mix.len() {
+      assert_eq!(recording.mix[i], stems[0][i] + stems[1][i] + stems[2][i] + stems[3][i]);
+    }
+    assert_eq!(recording.to_wav().len(), 44 + 125 * 4);
+    assert!(recording.stem_to_wav(4).is_some());
+    assert!(recording.stem_to_wav(5).is_none());
+    assert_eq!(audio.encode_recording(0), 0);
+  }
+
+  #[test]
+  fn mute_solo_and_gain() {
+    let mut audio = create_audio();
+    audio.enabled = true;
+    audio.set_sample_rate(4194304 / 4);
+    // Noise starts with bit 0 set, so only the square and wave are heard
+    audio.channel_2.set_duty_length(0xc0);
+    audio.channel_2.set_freq(1024);
+    audio.channel_2.control(0x80, 0xf0, 0);
+    audio.channel_3.ram = [0xff; 16];
+    audio.channel_3.set_dac(true);
+    audio.channel_3.set_volume_code(1);
+    audio.channel_3.set_freq(2047);
+    audio.channel_3.control(0x80, 0);
+    for _ in 0..(4096 / 16) {
+      audio.add_time(16);
+    }
+    audio.drain();
+
+    audio.add_time(4);
+    audio.set_channel_muted(2, true);
+    audio.add_time(4);
+    audio.set_channel_solo(2, true);
+    audio.add_time(4);
+    audio.set_channel_muted(2, false);
+    audio.set_channel_gain(2, 0.5);
+    audio.add_time(4);
+    audio.drain();
+    let left: Vec<f32> = audio.output.iter().step_by(2).cloned().collect();
+    assert_eq!(left, vec![0.5, 0.25, 0.0, 0.125]);
+
+    // Register state is untouched
+    assert!(audio.channel_2.enabled);
+    assert_eq!(audio.channel_status(), 0x6);
+  }
+
+  #[test]
+  fn ring_buffer_drops_oldest() {
+    let mut audio = create_audio();
+    audio.set_sample_rate(4194304 / 4);
+    for _ in 0..10000 {
+      audio.add_time(4);
+    }
+    assert_eq!(audio.available_frames(), 8191);
+    assert_eq!(audio.drain(), 8191);
+    assert_eq!(audio.output.len(), 8191 * 2);
+  }
+}
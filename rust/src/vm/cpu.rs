@@ -1,4 +1,7 @@
 use vm::memmap::MemMap;
+use vm::model;
+use vm::model::Model;
+use vm::random::Rng;
 
 pub struct CPU {
   a: u8,
@@ -96,15 +99,29 @@ impl CPU {
     self.pc = 0;
   }
 
-  pub fn simulate_bootloader(&mut self) {
-    self.a = 0x01;
-    self.b = 0;
-    self.c = 0x13;
-    self.d = 0;
-    self.e = 0xd8;
-    self.h = 0x01;
-    self.l = 0x4d;
-    self.flags = 0xb0;
+  // Registers are undefined at power on, the boot ROM sets what it needs
+  pub fn randomize_registers(&mut self, rng: &mut Rng) {
+    self.a = rng.next_byte();
+    self.b = rng.next_byte();
+    self.c = rng.next_byte();
+    self.d = rng.next_byte();
+    self.e = rng.next_byte();
+    self.h = rng.next_byte();
+    self.l = rng.next_byte();
+    self.flags = rng.next_byte() & 0xf0;
+    self.sp = rng.next() as u16;
+  }
+
+  pub fn simulate_bootloader(&mut self, model: Model) {
+    let registers = model::post_boot_registers(model);
+    self.a = registers.a;
+    self.b = registers.b;
+    self.c = registers.c;
+    self.d = registers.d;
+    self.e = registers.e;
+    self.h = registers.h;
+    self.l = registers.l;
+    self.flags = registers.flags;
     self.sp = 0xfffe;
     self.pc = 0x100;
   }
@@ -417,6 +434,13 @@ impl CPU {
     self.pc = 0x50;
   }
 
+  pub fn int_serial(&mut self, mem: &mut MemMap) {
+    self.disable_interrupts();
+    let pc = self.pc;
+    self.push(mem, pc);
+    self.pc = 0x58;
+  }
+
   pub fn int_joypad(&mut self, mem: &mut MemMap) {
     self.disable_interrupts();
     let pc = self.pc;
@@ -512,7 +536,12 @@ impl CPU {
           (1, 4)
       },
       0x10 => { // STOP
-          state = RunState::Stop;
+          if mem.is_speed_switch_armed() {
+            // CGB speed switch, execution continues at the new speed
+            mem.stop_for_speed_switch();
+          } else {
+            state = RunState::Stop;
+          }
           (1, 4)
       },
       0x11 => { // LD DE,nn
@@ -3652,7 +3681,22 @@ mod tests {
   use vm::cpu::create_cpu;
   use vm::cpu::Register8;
   use vm::cpu::Register16;
+  use vm::cpu::RunState;
   use vm::memmap::create_memmap;
+  use vm::model::Model;
+
+  #[test]
+  fn simulate_bootloader_per_model() {
+    let mut cpu = create_cpu();
+    cpu.simulate_bootloader(Model::Dmg);
+    assert_eq!(cpu.get_register_16(Register16::AF), 0x01b0);
+    assert_eq!(cpu.get_register_16(Register16::PC), 0x100);
+    cpu.simulate_bootloader(Model::Cgb);
+    assert_eq!(cpu.get_register_8(Register8::A), 0x11);
+    assert_eq!(cpu.get_register_16(Register16::DE), 0xff56);
+    cpu.simulate_bootloader(Model::Sgb);
+    assert_eq!(cpu.get_register_16(Register16::HL), 0xc060);
+  }
 
   #[test]
   fn get_register_8() {
@@ -3804,6 +3848,22 @@ mod tests {
     assert_eq!(cpu.get_register_8(Register8::C), 0xf0);
   }
 
+  #[test]
+  fn instruction_0x10() {
+    let mut cpu = create_cpu();
+    let mut mem = create_memmap(0);
+    cpu.pc = 0xc010;
+    mem.set_byte(0xc010, 0x10);
+    mem.set_byte(0xc011, 0x10);
+    let (state, _) = cpu.step(&mut mem);
+    assert_eq!(state, RunState::Stop);
+    mem.set_cgb_mode(true);
+    mem.set_byte(0xff4d, 1);
+    let (state, _) = cpu.step(&mut mem);
+    assert_eq!(state, RunState::Run);
+    assert!(mem.is_double_speed());
+  }
+
   #[test]
   fn instruction_0x11() {
     let mut cpu = create_cpu();
@@ -0,0 +1,284 @@
+use vm::cart::Cart;
+
+// GBS files are a 0x70 byte header followed by code and data, which is loaded
+// into ROM at the header's load address. INIT is called once per song and
+// PLAY at the timer or vblank rate.
+pub const HEADER_SIZE: usize = 0x70;
+
+// Return address for INIT and PLAY, a HALT loop the CPU idles in between calls
+pub const IDLE_ADDR: u16 = 0x70;
+
+// CPU cycles per frame at normal speed
+const VBLANK_PERIOD: u32 = 70224;
+const CLOCK_RATE: u64 = 4194304;
+
+#[derive(Debug, PartialEq)]
+pub enum GbsError {
+  TooShort,
+  BadMagic,
+  UnsupportedVersion(u8),
+  BadLoadAddress(u16),
+}
+
+pub struct Header {
+  pub song_count: u8,
+  // 1-based, as stored in the file
+  pub first_song: u8,
+  pub load_addr: u16,
+  pub init_addr: u16,
+  pub play_addr: u16,
+  pub stack_pointer: u16,
+  pub timer_modulo: u8,
+  pub timer_control: u8,
+  pub title: String,
+  pub author: String,
+  pub copyright: String,
+}
+
+impl Header {
+  // Bit 7 of the timer control asks for CGB double speed
+  pub fn is_double_speed(&self) -> bool {
+    return self.timer_control & 0x80 > 0;
+  }
+
+  // CPU cycles between PLAY calls. With the timer enabled in TAC, PLAY runs
+  // each time TIMA overflows, otherwise once a frame.
+  pub fn play_period(&self) -> u32 {
+    if self.timer_control & 0x4 == 0 {
+      return if self.is_double_speed() { VBLANK_PERIOD * 2 } else { VBLANK_PERIOD };
+    }
+    let divider = match self.timer_control & 0x3 {
+      0 => 1024,
+      1 => 16,
+      2 => 64,
+      _ => 256,
+    };
+    return divider * (256 - self.timer_modulo as u32);
+  }
+}
+
+pub fn parse_header(data: &[u8]) -> Result<Header, GbsError> {
+  if data.len() < HEADER_SIZE {
+    return Err(GbsError::TooShort);
+  }
+  if &data[0..3] != b"GBS" {
+    return Err(GbsError::BadMagic);
+  }
+  if data[3] != 1 {
+    return Err(GbsError::UnsupportedVersion(data[3]));
+  }
+  let load_addr = read_word(data, 0x06);
+  if load_addr < 0x400 || load_addr >= 0x8000 {
+    return Err(GbsError::BadLoadAddress(load_addr));
+  }
+  return Ok(Header {
+    song_count: data[0x04],
+    first_song: if data[0x05] > 0 { data[0x05] } else { 1 },
+    load_addr: load_addr,
+    init_addr: read_word(data, 0x08),
+    play_addr: read_word(data, 0x0a),
+    stack_pointer: read_word(data, 0x0c),
+    timer_modulo: data[0x0e],
+    timer_control: data[0x0f],
+    title: read_string(&data[0x10..0x30]),
+    author: read_string(&data[0x30..0x50]),
+    copyright: read_string(&data[0x50..0x70]),
+  });
+}
+
+fn read_word(data: &[u8], offset: usize) -> u16 {
+  return (data[offset] as u16) | ((data[offset + 1] as u16) << 8);
+}
+
+fn read_string(data: &[u8]) -> String {
+  let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+  return String::from_utf8_lossy(&data[..end]).into_owned();
+}
+
+// Lays the GBS data out in ROM behind an MBC3, which switches banks on any
+// write to 0x2000-0x3fff like GBS players expect. The space below the load
+// address holds the RST and interrupt vectors and the idle loop.
+pub fn load_rom(cart: &mut Cart, header: &Header, data: &[u8]) {
+  cart.set_mbc(0x12);
+  cart.enable_ram();
+  for byte in cart.raw_rom.iter_mut() {
+    *byte = 0;
+  }
+  let start = header.load_addr as usize;
+  let len = data.len().min(cart.raw_rom.len() - start);
+  cart.raw_rom[start..start + len].copy_from_slice(&data[..len]);
+
+  // RST n jumps to the matching vector past the load address
+  for rst in 0..8 {
+    let vector = rst * 8;
+    let target = header.load_addr + vector as u16;
+    cart.raw_rom[vector] = 0xc3;
+    cart.raw_rom[vector + 1] = target as u8;
+    cart.raw_rom[vector + 2] = (target >> 8) as u8;
+  }
+  // PLAY is driven directly, so interrupts just return
+  for vector in [0x40, 0x48, 0x50, 0x58, 0x60].iter() {
+    cart.raw_rom[*vector] = 0xd9;
+  }
+  // HALT, JR -3
+  let idle = IDLE_ADDR as usize;
+  cart.raw_rom[idle] = 0x76;
+  cart.raw_rom[idle + 1] = 0x18;
+  cart.raw_rom[idle + 2] = 0xfd;
+}
+
+pub struct GbsPlayer {
+  pub header: Header,
+  // 0-based, INIT receives it in A
+  pub track: u8,
+  // Seconds to play each track for, 0 plays forever
+  pub duration: u32,
+  // Real time cycles the current track has played for
+  pub elapsed: u64,
+  // CPU cycles until PLAY is called again
+  play_timer: u32,
+}
+
+impl GbsPlayer {
+  pub fn start_track(&mut self, track: u8) {
+    self.track = track;
+    self.elapsed = 0;
+    self.play_timer = self.header.play_period();
+  }
+
+  // Counts down to the next PLAY call, returning true when it's due
+  pub fn add_time(&mut self, cycles: u32, double_speed: bool) -> bool {
+    self.elapsed += if double_speed { cycles as u64 / 2 } else { cycles as u64 };
+    if cycles < self.play_timer {
+      self.play_timer -= cycles;
+      return false;
+    }
+    // An instruction can outlast a short period, the missed calls are skipped
+    let period = self.header.play_period();
+    let overrun = (cycles - self.play_timer) % period;
+    self.play_timer = period - overrun;
+    return true;
+  }
+
+  pub fn is_finished(&self) -> bool {
+    return self.duration > 0 && self.elapsed >= (self.duration as u64) * CLOCK_RATE;
+  }
+}
+
+pub fn create_player(header: Header) -> GbsPlayer {
+  let track = header.first_song - 1;
+  let period = header.play_period();
+  return GbsPlayer {
+    header: header,
+    track: track,
+    duration: 0,
+    elapsed: 0,
+    play_timer: period,
+  };
+}
+
+#[cfg(test)]
+mod tests {
+  use vm::create_vm;
+  use vm::gbs::create_player;
+  use vm::gbs::parse_header;
+  use vm::gbs::GbsError;
+  use vm::gbs::HEADER_SIZE;
+
+  fn build_gbs(timer_control: u8) -> Vec<u8> {
+    let mut data = vec![0; HEADER_SIZE];
+    data[0..4].copy_from_slice(b"GBS\x01");
+    // Three songs starting with the second, loaded at 0x400
+    data[0x04] = 3;
+    data[0x05] = 2;
+    data[0x06..0x0e].copy_from_slice(&[0x00, 0x04, 0x00, 0x04, 0x04, 0x04, 0xfe, 0xff]);
+    data[0x0e] = 0xc0;
+    data[0x0f] = timer_control;
+    data[0x10..0x14].copy_from_slice(b"Test");
+    data.extend_from_slice(&[
+      // INIT: LD (0xc000),A; RET
+      0xea, 0x00, 0xc0, 0xc9,
+      // PLAY: LD HL,0xc001; INC (HL); RET
+      0x21, 0x01, 0xc0, 0x34, 0xc9,
+    ]);
+    return data;
+  }
+
+  #[test]
+  fn parses_header() {
+    let header = parse_header(&build_gbs(0)).ok().unwrap();
+    assert_eq!(header.song_count, 3);
+    assert_eq!(header.first_song, 2);
+    assert_eq!(header.load_addr, 0x400);
+    assert_eq!(header.play_addr, 0x404);
+    assert_eq!(header.stack_pointer, 0xfffe);
+    assert_eq!(header.title, "Test");
+    assert_eq!(header.play_period(), 70224);
+
+    // TIMA overflows every 64 * (256 - 0xc0) cycles
+    let header = parse_header(&build_gbs(0x06)).ok().unwrap();
+    assert_eq!(header.play_period(), 4096);
+
+    assert_eq!(parse_header(&[0; 4]).err(), Some(GbsError::TooShort));
+    let mut data = build_gbs(0);
+    data[0x07] = 0x00;
+    assert_eq!(parse_header(&data).err(), Some(GbsError::BadLoadAddress(0)));
+  }
+
+  #[test]
+  fn play_period_shorter_than_an_instruction() {
+    // TIMA overflows every 16 cycles
+    let mut data = build_gbs(0x05);
+    data[0x0e] = 0xff;
+    let mut player = create_player(parse_header(&data).ok().unwrap());
+    assert_eq!(player.header.play_period(), 16);
+    // A 24 cycle CALL runs 8 cycles into the next period
+    assert!(player.add_time(24, false));
+    assert!(!player.add_time(4, false));
+    assert!(player.add_time(4, false));
+    // Overrunning several periods only calls PLAY once
+    assert!(player.add_time(40, false));
+    assert!(!player.add_time(4, false));
+    assert!(player.add_time(4, false));
+  }
+
+  #[test]
+  fn calls_init_and_play() {
+    let mut vm = create_vm();
+    assert_eq!(vm.load_gbs(&build_gbs(0)), Ok(()));
+    // INIT runs right away with the first song, PLAY a frame later
+    vm.gbs_frame();
+    assert_eq!(vm.mem.get_byte(0xc000), 1);
+    assert_eq!(vm.mem.get_byte(0xc001), 0);
+    vm.gbs_frame();
+    vm.gbs_frame();
+    assert_eq!(vm.mem.get_byte(0xc001), 2);
+
+    vm.start_gbs_track(2);
+    assert_eq!(vm.mem.get_byte(0xc001), 0);
+    vm.gbs_frame();
+    assert_eq!(vm.mem.get_byte(0xc000), 2);
+
+    // Faster timer-driven playback, for a limited time
+    assert_eq!(vm.load_gbs(&build_gbs(0x06)), Ok(()));
+    vm.gbs.as_mut().unwrap().duration = 1;
+    let mut frames = 0;
+    while !vm.gbs_frame() {
+      frames += 1;
+    }
+    assert_eq!(frames, 59);
+    // 1028 PLAY calls in the 60 frames run, 1024 a second
+    assert_eq!(vm.mem.get_byte(0xc001), (1028 & 0xff) as u8);
+  }
+
+  #[test]
+  fn cart_replaces_gbs() {
+    let mut vm = create_vm();
+    vm.breakpoints.push(0x150);
+    assert_eq!(vm.load_gbs(&build_gbs(0)), Ok(()));
+    assert_eq!(vm.breakpoints, vec![0x100, 0x150]);
+    vm.reset_after_bootloader(vm.cart_model());
+    vm.apply_cart_header();
+    assert!(vm.gbs.is_none());
+  }
+}
@@ -1,11 +1,15 @@
 use vm::memmap::MemMap;
+use vm::palette;
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
 
 #[derive(Debug, PartialEq)]
 pub enum GPUAction {
   Noop,
   RenderScanline(u8),
   IncrementLine(u8),
-  FlushBuffer, // implies line 143
+  FlushBuffer, // implies line 144
 }
 
 enum GPUMode {
@@ -19,13 +23,27 @@ pub struct GPU {
   mode: GPUMode,
   time: u16,
   line: u8,
+  window_line: u8,
+
+  // RGB values for each of the four shades, indexed by palette::Layer
+  pub palettes: [[u32; 4]; 3],
+  // Staging area for palette files copied in by the host
+  pub palette_file: [u8; 0x400],
+  // RGBA pixels, one row of 160 pixels per scanline
+  pub framebuffer: Box<[u8; SCREEN_WIDTH * SCREEN_HEIGHT * 4]>,
 }
 
 pub fn create_gpu() -> GPU {
+  let green = palette::preset_colors(palette::Preset::OriginalGreen);
   return GPU {
     mode: GPUMode::Mode2,
     time: 0,
     line: 0,
+    window_line: 0,
+
+    palettes: [green, green, green],
+    palette_file: [0; 0x400],
+    framebuffer: box [0xff; SCREEN_WIDTH * SCREEN_HEIGHT * 4],
   };
 }
 
@@ -34,6 +52,39 @@ impl GPU {
     return self.line;
   }
 
+  pub fn set_palette_color(&mut self, layer: palette::Layer, shade: u8, rgb: u32) {
+    self.palettes[layer as usize][(shade & 0x3) as usize] = rgb & 0xffffff;
+  }
+
+  pub fn get_palette_color(&self, layer: palette::Layer, shade: u8) -> u32 {
+    return self.palettes[layer as usize][(shade & 0x3) as usize];
+  }
+
+  pub fn apply_palette_preset(&mut self, preset: palette::Preset) {
+    let colors = palette::preset_colors(preset);
+    self.palettes = [colors, colors, colors];
+  }
+
+  pub fn load_palette_file(&mut self, data: &[u8]) -> Result<(), palette::PaletteError> {
+    self.palettes = palette::parse_palette_file(data)?;
+    return Ok(());
+  }
+
+  pub fn framebuffer_ptr(&mut self) -> *mut u8 {
+    let ptr = &mut self.framebuffer[0] as *mut u8;
+    return ptr;
+  }
+
+  pub fn palette_ptr(&mut self) -> *mut u32 {
+    let ptr = &mut self.palettes[0][0] as *mut u32;
+    return ptr;
+  }
+
+  pub fn palette_file_ptr(&mut self) -> *mut u8 {
+    let ptr = &mut self.palette_file[0] as *mut u8;
+    return ptr;
+  }
+
   pub fn add_clock_time(&mut self, mem: &mut MemMap, time: u8) -> GPUAction {
     self.time += time as u16;
     return match self.mode {
@@ -51,6 +102,8 @@ impl GPU {
           self.time = 0;
           self.mode = GPUMode::Mode0;
           mem.zero_page[0x41] = mem.zero_page[0x41] & 0xfc;
+          self.render_scanline(mem);
+          mem.hblank_dma();
           GPUAction::RenderScanline(self.line)
         } else {
           GPUAction::Noop
@@ -61,11 +114,12 @@ impl GPU {
         if self.time >= 204 {
           self.time = 0;
           self.line += 1;
-          if self.line >= 143 {
+          if self.line >= 144 {
             self.mode = GPUMode::Mode1;
             mem.zero_page[0x41] = (mem.zero_page[0x41] & 0xfc) | 1;
             // Enable vblank interrupt
             mem.zero_page[0x0f] = mem.zero_page[0x0f] | 1;
+            mem.sgb_vblank(&self.framebuffer[..]);
             GPUAction::FlushBuffer
           } else {
             self.mode = GPUMode::Mode2;
@@ -83,6 +137,7 @@ impl GPU {
           self.line += 1;
           if self.line > 153 {
             self.line = 0;
+            self.window_line = 0;
             self.mode = GPUMode::Mode2;
             mem.zero_page[0x41] = (mem.zero_page[0x41] & 0xfc) | 2;
             mem.zero_page[0x0f] = mem.zero_page[0x0f] & 0xfe;
@@ -94,4 +149,260 @@ impl GPU {
       },
     }
   }
-}
\ No newline at end of file
+
+  pub fn render_scanline(&mut self, mem: &MemMap) {
+    let line = self.line as usize;
+    if line >= SCREEN_HEIGHT {
+      return;
+    }
+    let control = mem.zero_page[0x40];
+    let cgb = mem.cgb_mode;
+    let mut pixels = [Pixel { color: 0, palette: 0, layer: palette::Layer::Background, priority: false }; SCREEN_WIDTH];
+
+    if control & 0x80 == 0 {
+      // LCD is off, the screen is blank
+      self.write_line(mem, &pixels);
+      return;
+    }
+
+    // On CGB, bit 0 removes background priority rather than the background
+    if cgb || control & 0x1 > 0 {
+      let scroll_y = mem.zero_page[0x42];
+      let scroll_x = mem.zero_page[0x43];
+      let map = if control & 0x8 > 0 { 0x1c00 } else { 0x1800 };
+      let y = scroll_y.wrapping_add(self.line);
+      for x in 0..SCREEN_WIDTH {
+        let bg_x = scroll_x.wrapping_add(x as u8);
+        pixels[x] = tile_map_pixel(mem, control, map, bg_x, y);
+      }
+
+      let window_y = mem.zero_page[0x4a];
+      let window_x = mem.zero_page[0x4b] as i16 - 7;
+      if control & 0x20 > 0 && self.line >= window_y && window_x < SCREEN_WIDTH as i16 {
+        let map = if control & 0x40 > 0 { 0x1c00 } else { 0x1800 };
+        let start = if window_x < 0 { 0 } else { window_x as usize };
+        for x in start..SCREEN_WIDTH {
+          let win_x = (x as i16 - window_x) as u8;
+          pixels[x] = tile_map_pixel(mem, control, map, win_x, self.window_line);
+        }
+        self.window_line += 1;
+      }
+    }
+
+    if control & 0x2 > 0 {
+      self.render_sprites(mem, control, &mut pixels);
+    }
+
+    self.write_line(mem, &pixels);
+  }
+
+  fn render_sprites(&self, mem: &MemMap, control: u8, pixels: &mut [Pixel; SCREEN_WIDTH]) {
+    let cgb = mem.cgb_mode;
+    let height = if control & 0x4 > 0 { 16 } else { 8 };
+    let line = self.line as i16;
+    // Only the first ten sprites on a line, in OAM order, are drawn
+    let mut visible = Vec::with_capacity(10);
+    for i in 0..40 {
+      let y = mem.sprite_table[i * 4] as i16 - 16;
+      if line >= y && line < y + height {
+        visible.push(i);
+        if visible.len() >= 10 {
+          break;
+        }
+      }
+    }
+    if !cgb {
+      // Lower X coordinates win, ties go to the lower OAM index
+      visible.sort_by_key(|&i| (mem.sprite_table[i * 4 + 1], i));
+    }
+
+    let mut drawn = [false; SCREEN_WIDTH];
+    for &i in visible.iter() {
+      let y = mem.sprite_table[i * 4] as i16 - 16;
+      let x = mem.sprite_table[i * 4 + 1] as i16 - 8;
+      let mut tile = mem.sprite_table[i * 4 + 2];
+      let attributes = mem.sprite_table[i * 4 + 3];
+      if height == 16 {
+        tile = tile & 0xfe;
+      }
+      let mut row = (line - y) as u16;
+      if attributes & 0x40 > 0 {
+        row = (height - 1) as u16 - row;
+      }
+      let bank = if cgb && attributes & 0x8 > 0 { 0x2000 } else { 0 };
+      let addr = bank + (tile as usize) * 16 + (row as usize) * 2;
+      let low = mem.video_ram[addr];
+      let high = mem.video_ram[addr + 1];
+      for col in 0..8 {
+        let screen_x = x + col;
+        if screen_x < 0 || screen_x >= SCREEN_WIDTH as i16 {
+          continue;
+        }
+        let sx = screen_x as usize;
+        if drawn[sx] {
+          continue;
+        }
+        let bit = if attributes & 0x20 > 0 { col } else { 7 - col };
+        let color = ((low >> bit) & 1) | (((high >> bit) & 1) << 1);
+        if color == 0 {
+          continue;
+        }
+        drawn[sx] = true;
+        let bg = pixels[sx];
+        let bg_wins = if cgb {
+          control & 0x1 > 0 && (bg.priority || attributes & 0x80 > 0)
+        } else {
+          attributes & 0x80 > 0
+        };
+        if bg_wins && bg.color != 0 {
+          // Sprite is hidden behind non-zero background colors
+          continue;
+        }
+        pixels[sx] = Pixel {
+          color: color,
+          palette: if cgb { attributes & 0x7 } else { (attributes & 0x10) >> 4 },
+          layer: if attributes & 0x10 > 0 && !cgb { palette::Layer::Object1 } else { palette::Layer::Object0 },
+          priority: false,
+        };
+      }
+    }
+  }
+
+  fn write_line(&mut self, mem: &MemMap, pixels: &[Pixel; SCREEN_WIDTH]) {
+    let offset = (self.line as usize) * SCREEN_WIDTH * 4;
+    for x in 0..SCREEN_WIDTH {
+      let pixel = pixels[x];
+      let rgb = if mem.cgb_mode {
+        let ram = match pixel.layer {
+          palette::Layer::Background => &mem.bg_palette_ram,
+          _ => &mem.obj_palette_ram,
+        };
+        let index = ((pixel.palette as usize) * 4 + (pixel.color as usize)) * 2;
+        palette::cgb_color_to_rgb((ram[index] as u16) | ((ram[index + 1] as u16) << 8))
+      } else {
+        let register = match pixel.layer {
+          palette::Layer::Background => mem.zero_page[0x47],
+          palette::Layer::Object0 => mem.zero_page[0x48],
+          palette::Layer::Object1 => mem.zero_page[0x49],
+        };
+        let shade = (register >> (pixel.color * 2)) & 0x3;
+        if mem.sgb.enabled {
+          mem.sgb.colorize(x, self.line as usize, shade)
+        } else {
+          self.palettes[pixel.layer as usize][shade as usize]
+        }
+      };
+      let out = offset + x * 4;
+      self.framebuffer[out] = (rgb >> 16) as u8;
+      self.framebuffer[out + 1] = (rgb >> 8) as u8;
+      self.framebuffer[out + 2] = rgb as u8;
+      self.framebuffer[out + 3] = 0xff;
+    }
+  }
+}
+
+#[derive(Copy, Clone)]
+struct Pixel {
+  // Color index (0-3) before palettes are applied
+  color: u8,
+  // CGB palette number
+  palette: u8,
+  layer: palette::Layer,
+  // CGB background tiles can be drawn above sprites
+  priority: bool,
+}
+
+fn tile_map_pixel(mem: &MemMap, control: u8, map: usize, x: u8, y: u8) -> Pixel {
+  let map_index = map + ((y as usize) / 8) * 32 + (x as usize) / 8;
+  let tile = mem.video_ram[map_index];
+  // CGB tile attributes live in the same spot in VRAM bank 1
+  let attributes = if mem.cgb_mode { mem.video_ram[0x2000 + map_index] } else { 0 };
+  let bank = if attributes & 0x8 > 0 { 0x2000 } else { 0 };
+  let tile_addr = if control & 0x10 > 0 {
+    (tile as usize) * 16
+  } else {
+    // Signed tile indices relative to 0x9000
+    (0x1000 + (tile as i8 as i32) * 16) as usize
+  };
+  let tile_y = if attributes & 0x40 > 0 { 7 - (y % 8) } else { y % 8 };
+  let tile_x = if attributes & 0x20 > 0 { 7 - (x % 8) } else { x % 8 };
+  let row = bank + tile_addr + (tile_y as usize) * 2;
+  let bit = 7 - tile_x;
+  let low = (mem.video_ram[row] >> bit) & 1;
+  let high = (mem.video_ram[row + 1] >> bit) & 1;
+  return Pixel {
+    color: low | (high << 1),
+    palette: attributes & 0x7,
+    layer: palette::Layer::Background,
+    priority: attributes & 0x80 > 0,
+  };
+}
+
+#[cfg(test)]
+mod tests {
+  use vm::gpu::create_gpu;
+  use vm::memmap::create_memmap;
+  use vm::palette::Layer;
+  use vm::palette::Preset;
+
+  #[test]
+  fn renders_background_through_palette() {
+    let mut gpu = create_gpu();
+    let mut mem = create_memmap(0);
+    // Tile 1 is solid color 3, and is placed in the top left of map 0
+    for i in 0..16 {
+      mem.video_ram[16 + i] = 0xff;
+    }
+    mem.video_ram[0x1800] = 1;
+    mem.zero_page[0x40] = 0x91;
+    mem.zero_page[0x47] = 0xe4;
+    gpu.apply_palette_preset(Preset::HighContrast);
+    gpu.set_palette_color(Layer::Background, 3, 0x123456);
+    gpu.render_scanline(&mem);
+    assert_eq!(&gpu.framebuffer[0..4], &[0x12, 0x34, 0x56, 0xff]);
+    assert_eq!(&gpu.framebuffer[32..36], &[0xff, 0xff, 0xff, 0xff]);
+  }
+
+  #[test]
+  fn renders_sprites_with_object_palettes() {
+    let mut gpu = create_gpu();
+    let mut mem = create_memmap(0);
+    for i in 0..16 {
+      mem.video_ram[32 + i] = 0xff;
+    }
+    mem.zero_page[0x40] = 0x93;
+    mem.zero_page[0x47] = 0xe4;
+    mem.zero_page[0x49] = 0xc0;
+    // Sprite 0 at (4, 0) using tile 2 and OBP1
+    mem.sprite_table[0] = 16;
+    mem.sprite_table[1] = 12;
+    mem.sprite_table[2] = 2;
+    mem.sprite_table[3] = 0x10;
+    gpu.set_palette_color(Layer::Object1, 3, 0xff0000);
+    gpu.render_scanline(&mem);
+    assert_eq!(gpu.framebuffer[3 * 4], 0x9b);
+    assert_eq!(&gpu.framebuffer[4 * 4..4 * 4 + 4], &[0xff, 0x00, 0x00, 0xff]);
+    assert_eq!(&gpu.framebuffer[11 * 4..11 * 4 + 4], &[0xff, 0x00, 0x00, 0xff]);
+    assert_eq!(gpu.framebuffer[12 * 4], 0x9b);
+  }
+
+  #[test]
+  fn renders_cgb_attributes_and_palettes() {
+    let mut gpu = create_gpu();
+    let mut mem = create_memmap(0);
+    mem.set_cgb_mode(true);
+    // Tile 1 in bank 1 has color 1 in its leftmost column only
+    for i in 0..8 {
+      mem.video_ram[0x2000 + 16 + i * 2] = 0x80;
+    }
+    mem.video_ram[0x1800] = 1;
+    // Bank 1, palette 2, horizontally flipped
+    mem.video_ram[0x3800] = 0x8 | 0x2 | 0x20;
+    mem.bg_palette_ram[(2 * 4 + 1) * 2] = 0x1f;
+    mem.bg_palette_ram[(2 * 4 + 1) * 2 + 1] = 0x00;
+    mem.zero_page[0x40] = 0x91;
+    gpu.render_scanline(&mem);
+    assert_eq!(&gpu.framebuffer[7 * 4..7 * 4 + 4], &[0xff, 0x00, 0x00, 0xff]);
+    assert_eq!(&gpu.framebuffer[0..4], &[0xff, 0xff, 0xff, 0xff]);
+  }
+}
@@ -0,0 +1,230 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::rc::Rc;
+use vm::gpu::GPUAction;
+use vm::serial::SerialDevice;
+use vm::VM;
+
+// State shared by both ends of the cable
+struct Cable {
+  // Contents of each side's SB, refreshed before every step
+  data: [u8; 2],
+  // Time on each side, in half cycles so double speed can be compared
+  time: [u64; 2],
+  // Byte clocked in by the other side, and when it finishes arriving
+  incoming: [Option<(u8, u64)>; 2],
+  // Set when a side starts clocking a byte, until its clock speed is known
+  started: [bool; 2],
+}
+
+// The serial device each VM sees. Whichever side uses its internal clock is
+// the master; the other side receives on the master's clock.
+pub struct LinkPort {
+  cable: Rc<RefCell<Cable>>,
+  side: usize,
+}
+
+impl SerialDevice for LinkPort {
+  fn exchange(&mut self, byte: u8) -> u8 {
+    let mut cable = self.cable.borrow_mut();
+    let other = 1 - self.side;
+    let start = cable.time[self.side];
+    cable.incoming[other] = Some((byte, start));
+    cable.started[self.side] = true;
+    return cable.data[other];
+  }
+
+  fn poll(&mut self, _byte: u8) -> Option<u8> {
+    let mut cable = self.cable.borrow_mut();
+    let now = cable.time[self.side];
+    return match cable.incoming[self.side] {
+      Some((value, done)) if now >= done => {
+        cable.incoming[self.side] = None;
+        Some(value)
+      },
+      _ => None,
+    };
+  }
+
+  fn as_any(&mut self) -> &mut dyn Any {
+    return self;
+  }
+}
+
+pub struct Link {
+  cable: Rc<RefCell<Cable>>,
+}
+
+// Plugs a cable into both VMs, replacing whatever was on their serial ports
+pub fn create_link(a: &mut VM, b: &mut VM) -> Link {
+  let cable = Rc::new(RefCell::new(Cable {
+    data: [a.mem.serial.data, b.mem.serial.data],
+    time: [0, 0],
+    incoming: [None, None],
+    started: [false, false],
+  }));
+  a.mem.serial.device = Some(Box::new(LinkPort { cable: cable.clone(), side: 0 }));
+  b.mem.serial.device = Some(Box::new(LinkPort { cable: cable.clone(), side: 1 }));
+  return Link {
+    cable: cable,
+  };
+}
+
+impl Link {
+  // True if `a` and `b` are plugged into this cable, in the order it was
+  // created with
+  pub fn is_attached(&self, a: &mut VM, b: &mut VM) -> bool {
+    return self.is_port(a, 0) && self.is_port(b, 1);
+  }
+
+  fn is_port(&self, vm: &mut VM, side: usize) -> bool {
+    return match vm.mem.serial.device {
+      Some(ref mut device) => match device.as_any().downcast_mut::<LinkPort>() {
+        Some(port) => port.side == side && Rc::ptr_eq(&port.cable, &self.cable),
+        None => false,
+      },
+      None => false,
+    };
+  }
+
+  // Runs both machines until each has produced a frame, always stepping the
+  // one that is behind so neither gets more than an instruction ahead.
+  // Returns true if either hit a breakpoint.
+  pub fn frame(&mut self, a: &mut VM, b: &mut VM) -> bool {
+    let mut done = [false, false];
+    let mut paused = [false, false];
+    while !done[0] || !done[1] {
+      let side = {
+        let cable = self.cable.borrow();
+        if done[1] || (!done[0] && cable.time[0] <= cable.time[1]) { 0 } else { 1 }
+      };
+      let vm = if side == 0 { &mut *a } else { &mut *b };
+      self.cable.borrow_mut().data[side] = vm.mem.serial.data;
+      let double_speed = vm.mem.is_double_speed();
+      let (cycles, action, hit) = vm.tick(paused[side]);
+      paused[side] = paused[side] || hit;
+      self.clock_transfer(side, vm, double_speed);
+      self.deliver_unarmed(side, vm);
+      let mut cable = self.cable.borrow_mut();
+      cable.time[side] += if double_speed { cycles as u64 } else { cycles as u64 * 2 };
+      if action == GPUAction::FlushBuffer {
+        done[side] = true;
+      }
+    }
+    return paused[0] || paused[1];
+  }
+
+  // Works out when a byte the master just started clocking reaches the slave,
+  // from the master's SC clock select and CPU speed
+  fn clock_transfer(&mut self, side: usize, vm: &VM, double_speed: bool) {
+    let mut cable = self.cable.borrow_mut();
+    if !cable.started[side] {
+      return;
+    }
+    cable.started[side] = false;
+    let cycles = 8 * vm.mem.serial.bit_time() as u64;
+    let time = if double_speed { cycles } else { cycles * 2 };
+    let other = 1 - side;
+    if let Some((value, start)) = cable.incoming[other] {
+      cable.incoming[other] = Some((value, start + time));
+    }
+  }
+
+  // The master shifts the slave's register even when the slave hasn't started
+  // a transfer, it just doesn't get an interrupt
+  fn deliver_unarmed(&mut self, side: usize, vm: &mut VM) {
+    let serial = &mut vm.mem.serial;
+    if serial.is_transferring() && serial.control & 0x1 == 0 {
+      return;
+    }
+    let mut cable = self.cable.borrow_mut();
+    let now = cable.time[side];
+    if let Some((value, done)) = cable.incoming[side] {
+      if now >= done {
+        serial.data = value;
+        cable.incoming[side] = None;
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use vm::create_vm;
+  use vm::link::create_link;
+  use vm::VM;
+
+  fn load_program(vm: &mut VM, program: &[u8]) {
+    vm.breakpoints.clear();
+    // Skip the boot ROM
+    vm.mem.zero_page[0x50] = 1;
+    for (i, byte) in program.iter().enumerate() {
+      vm.mem.cart.raw_rom[i] = *byte;
+    }
+  }
+
+  #[test]
+  fn exchanges_bytes_between_vms() {
+    let mut master = create_vm();
+    let mut slave = create_vm();
+    // ld a, 0x12; ldh (0x01), a; ld a, 0x81; ldh (0x02), a; jr -2
+    load_program(&mut master, &[0x3e, 0x12, 0xe0, 0x01, 0x3e, 0x81, 0xe0, 0x02, 0x18, 0xfe]);
+    load_program(&mut slave, &[0x3e, 0x34, 0xe0, 0x01, 0x3e, 0x80, 0xe0, 0x02, 0x18, 0xfe]);
+    let mut link = create_link(&mut master, &mut slave);
+    link.frame(&mut master, &mut slave);
+    assert_eq!(master.mem.get_byte(0xff01), 0x34);
+    assert_eq!(slave.mem.get_byte(0xff01), 0x12);
+    assert_eq!(master.mem.get_byte(0xff0f) & 8, 8);
+    assert_eq!(slave.mem.get_byte(0xff0f) & 8, 8);
+    assert_eq!(master.mem.get_byte(0xff02) & 0x80, 0);
+    assert_eq!(slave.mem.get_byte(0xff02) & 0x80, 0);
+  }
+
+  #[test]
+  fn slave_receives_on_master_fast_clock() {
+    let mut master = create_vm();
+    let mut slave = create_vm();
+    master.mem.set_cgb_mode(true);
+    // Same as above, but on the CGB fast clock
+    load_program(&mut master, &[0x3e, 0x12, 0xe0, 0x01, 0x3e, 0x83, 0xe0, 0x02, 0x18, 0xfe]);
+    // Counts polls of SC in B until the transfer completes, then stores it
+    load_program(&mut slave, &[
+      0x3e, 0x34, 0xe0, 0x01, 0x3e, 0x80, 0xe0, 0x02, 0x06, 0x00,
+      0x04, 0xf0, 0x02, 0xcb, 0x7f, 0x20, 0xf9,
+      0x78, 0xea, 0x00, 0xc0, 0x18, 0xfe,
+    ]);
+    let mut link = create_link(&mut master, &mut slave);
+    link.frame(&mut master, &mut slave);
+    assert_eq!(slave.mem.get_byte(0xff01), 0x12);
+    // 128 cycles is only a few polls, the normal clock would take over 100
+    assert!(slave.mem.get_byte(0xc000) < 8);
+  }
+
+  #[test]
+  fn knows_which_vms_are_attached() {
+    let mut a = create_vm();
+    let mut b = create_vm();
+    let mut other = create_vm();
+    let link = create_link(&mut a, &mut b);
+    assert!(link.is_attached(&mut a, &mut b));
+    assert!(!link.is_attached(&mut b, &mut a));
+    assert!(!link.is_attached(&mut a, &mut other));
+    // A second cable takes over both ports
+    create_link(&mut a, &mut b);
+    assert!(!link.is_attached(&mut a, &mut b));
+  }
+
+  #[test]
+  fn slave_waits_for_master_clock() {
+    let mut master = create_vm();
+    let mut slave = create_vm();
+    // Master never starts a transfer
+    load_program(&mut master, &[0x00, 0x00, 0x18, 0xfe]);
+    load_program(&mut slave, &[0x3e, 0x34, 0xe0, 0x01, 0x3e, 0x80, 0xe0, 0x02, 0x18, 0xfe]);
+    let mut link = create_link(&mut master, &mut slave);
+    link.frame(&mut master, &mut slave);
+    link.frame(&mut master, &mut slave);
+    assert_eq!(slave.mem.get_byte(0xff02) & 0x80, 0x80);
+    assert_eq!(slave.mem.get_byte(0xff0f) & 8, 0);
+  }
+}
@@ -1,39 +1,86 @@
 use vm::audio;
 use vm::cart;
-
-#[cfg(not(test))]
-extern "C" {
-  fn set_master_gain(left: u8, right: u8);
-  fn audio_enabled(flag: u8);
-}
-
-#[cfg(test)]
-fn set_master_gain(left: u8, right: u8) {}
-#[cfg(test)]
-fn audio_enabled(flag: u8) {}
+use vm::model;
+use vm::model::Model;
+use vm::random;
+use vm::serial;
+use vm::sgb;
+use vm::vgm;
 
 #[derive(Debug, PartialEq)]
 enum KeySelect {
   Buttons,
   Directions,
+  None,
+}
+
+// Bits of 0xff10-0xff2f that can't be read back
+const AUDIO_READ_MASKS: [u8; 0x20] = [
+  0x80, 0x3f, 0x00, 0xff, 0xbf, 0xff, 0x3f, 0x00,
+  0xff, 0xbf, 0x7f, 0xff, 0x9f, 0xff, 0xbf, 0xff,
+  0xff, 0x00, 0x00, 0xbf, 0x00, 0x00, 0x70, 0xff,
+  0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+];
+
+// CPU cycles spent waiting for the clock to settle after a speed switch
+const SPEED_SWITCH_STALL: u32 = 2050 * 4;
+
+fn is_length_register(addr: u16) -> bool {
+  return addr == 0xff11 || addr == 0xff16 || addr == 0xff1b || addr == 0xff20;
 }
 
 pub struct MemMap {
-  pub boot: [u8; 0x100],
-  pub video_ram: [u8; 0x2000],
-  pub work_ram: [u8; 0x2000],
+  pub boot: [u8; 0x900],
+  boot_size: usize,
+  pub video_ram: [u8; 0x4000],
+  pub work_ram: [u8; 0x8000],
   pub sprite_table: [u8; 0xa0],
   pub zero_page: [u8; 0x100],
 
   pub cart: cart::Cart,
 
-  keys_buttons: u8,
-  keys_directions: u8,
+  // One pad per player, SGB multiplayer reads cycle through them
+  keys_buttons: [u8; 4],
+  keys_directions: [u8; 4],
   key_select: KeySelect,
+  current_player: u8,
 
   timer: u16,
 
+  dma_active: bool,
+  dma_pending: bool,
+  dma_source: u16,
+  dma_index: u16,
+  dma_time: u16,
+
   pub audio: audio::Audio,
+  // Records APU register writes while set
+  pub vgm: Option<vgm::VgmLogger>,
+  // Finished VGM file, read through a pointer
+  pub vgm_output: Vec<u8>,
+
+  pub serial: serial::Serial,
+
+  // Game Boy Color state, only reachable when cgb_mode is set
+  pub cgb_mode: bool,
+  vram_bank: u8,
+  wram_bank: u8,
+  pub bg_palette_ram: [u8; 0x40],
+  pub obj_palette_ram: [u8; 0x40],
+
+  // CGB VRAM DMA
+  hdma_source: u16,
+  hdma_dest: u16,
+  hdma_blocks: u8,
+  hdma_active: bool,
+  // CPU cycles to sit out while VRAM DMA holds the bus
+  cpu_stall: u32,
+
+  // Super Game Boy, listening to packets sent over the joypad register
+  pub sgb: sgb::Sgb,
+
+  // When set, VRAM and OAM are locked while the PPU is reading them
+  pub restrict_vram_access: bool,
 
   pub cart_ram_dirty: bool,
   pub tile_map_0_dirty: bool,
@@ -43,21 +90,49 @@ pub struct MemMap {
 
 pub fn create_memmap(mbc: u8) -> MemMap {
   return MemMap {
-    boot: [0; 0x100],
-    video_ram: [0; 0x2000],
-    work_ram: [0; 0x2000],
+    boot: [0; 0x900],
+    boot_size: 0x100,
+    video_ram: [0; 0x4000],
+    work_ram: [0; 0x8000],
     sprite_table: [0; 0xa0],
     zero_page: [0; 0x100],
 
     cart: cart::create_cart(mbc),
 
-    keys_buttons: 0x0f,
-    keys_directions: 0x0f,
+    keys_buttons: [0x0f; 4],
+    keys_directions: [0x0f; 4],
     key_select: KeySelect::Buttons,
+    current_player: 0,
 
     timer: 0,
 
+    dma_active: false,
+    dma_pending: false,
+    dma_source: 0,
+    dma_index: 0,
+    dma_time: 0,
+
     audio: audio::create_audio(),
+    vgm: None,
+    vgm_output: Vec::new(),
+
+    serial: serial::create_serial(),
+
+    cgb_mode: false,
+    vram_bank: 0,
+    wram_bank: 1,
+    bg_palette_ram: [0xff; 0x40],
+    obj_palette_ram: [0; 0x40],
+
+    hdma_source: 0,
+    hdma_dest: 0,
+    hdma_blocks: 0,
+    hdma_active: false,
+    cpu_stall: 0,
+
+    sgb: sgb::create_sgb(),
+
+    restrict_vram_access: true,
 
     cart_ram_dirty: false,
     tile_map_0_dirty: true,
@@ -67,16 +142,54 @@ pub fn create_memmap(mbc: u8) -> MemMap {
 }
 
 impl MemMap {
-  pub fn simulate_bootloader(&mut self) {
+  pub fn initialize_ram(&mut self, rng: &mut random::Rng, init: random::RamInit) {
+    rng.fill(&mut self.work_ram, init);
+    rng.fill(&mut self.video_ram, init);
+    rng.fill(&mut self.sprite_table, init);
+    // HRAM only, the I/O registers have fixed power-on values
+    rng.fill(&mut self.zero_page[0x80..0xff], init);
+    self.tile_data_dirty = true;
+    self.tile_map_0_dirty = true;
+    self.tile_map_1_dirty = true;
+  }
+
+  pub fn map_boot_rom(&mut self, len: usize) {
+    self.boot_size = if len > 0x100 { 0x900 } else { 0x100 };
+    self.zero_page[0x50] = 0;
+    // The CGB boot ROM starts in CGB mode and drops to DMG mode through KEY0
+    self.set_cgb_mode(self.boot_size > 0x100);
+  }
+
+  // Until 0xff50 is written, the boot ROM is in charge of the hardware mode
+  pub fn is_boot_rom_running(&self) -> bool {
+    return self.zero_page[0x50] == 0;
+  }
+
+  fn is_boot_rom_mapped(&self, addr: u16) -> bool {
+    if !self.is_boot_rom_running() {
+      return false;
+    }
+    return addr < 0x100 || (self.boot_size > 0x100 && addr >= 0x200 && addr < 0x900);
+  }
+
+  pub fn simulate_bootloader(&mut self, model: Model) {
     // Modify zero-page RAM to simulate the state after the bootloader has run
+    let io = model::post_boot_io(model);
+    self.zero_page[0x04] = io.div;
+    self.set_byte(0xff0f, 0xe1);
     self.set_byte(0xff05, 0x00);
     self.set_byte(0xff06, 0x00);
     self.set_byte(0xff07, 0x00);
+    // The APU ignores its other registers while powered off. Cycling the
+    // power first silences anything left over from before the reset.
+    self.set_byte(0xff26, 0x00);
+    self.set_byte(0xff26, 0x80);
     self.set_byte(0xff10, 0x80);
     self.set_byte(0xff11, 0x80);
     self.set_byte(0xff12, 0xf3);
     self.set_byte(0xff13, 0xc1);
-    self.set_byte(0xff14, 0x87);
+    // Triggering channel 1 leaves it playing, like the startup sound does
+    self.set_byte(0xff14, if io.nr52 & 0x1 > 0 { 0x87 } else { 0x07 });
     self.set_byte(0xff16, 0x3f);
     self.set_byte(0xff17, 0x00);
     self.set_byte(0xff19, 0xbf);
@@ -90,12 +203,13 @@ impl MemMap {
     self.set_byte(0xff23, 0xbf);
     self.set_byte(0xff24, 0x77);
     self.set_byte(0xff25, 0xf3);
-    self.set_byte(0xff26, 0x80);
     self.set_byte(0xff40, 0x91);
     self.set_byte(0xff42, 0x00);
     self.set_byte(0xff43, 0x00);
     self.set_byte(0xff44, 0x8f);
     self.set_byte(0xff45, 0x00);
+    // Written directly, storing to 0xff46 would start a DMA
+    self.zero_page[0x46] = io.dma;
     self.set_byte(0xff47, 0xfc);
     self.set_byte(0xff48, 0xff);
     self.set_byte(0xff49, 0xff);
@@ -109,56 +223,95 @@ impl MemMap {
   }
 
   pub fn get_byte(&self, addr: u16) -> u8 {
-    if addr < 0x100 {
-      if self.zero_page[0x50] == 0 {
-        return self.boot[addr as usize];
-      } else {
-        return self.cart.get_rom_byte(addr);
-      }
+    if self.dma_active && addr < 0xff00 {
+      return self.get_byte_during_dma(addr);
+    }
+    if self.is_boot_rom_mapped(addr) {
+      return self.boot[addr as usize];
     }
     if addr < 0x8000 {
       return self.cart.get_rom_byte(addr);
     }
     if addr < 0xa000 {
-      return self.video_ram[(addr - 0x8000) as usize];
+      if !self.vram_accessible() {
+        return 0xff;
+      }
+      return self.video_ram[self.vram_index(addr)];
     }
     if addr < 0xc000 {
       return self.cart.get_ram_byte(addr - 0xa000);
     }
     if addr < 0xe000 {
-      return self.work_ram[(addr - 0xc000) as usize];
+      return self.work_ram[self.wram_index(addr)];
     }
     if addr < 0xfe00 {
-      return self.work_ram[(addr - 0xe000) as usize];
+      return self.work_ram[self.wram_index(addr - 0x2000)];
     }
     if addr < 0xfea0 {
+      if !self.oam_accessible() {
+        return 0xff;
+      }
       return self.sprite_table[(addr - 0xfe00) as usize];
     }
     if addr < 0xff00 {
       // Inaccessible
       return 0xff;
     }
+    if addr >= 0xff4d && addr < 0xff80 {
+      return self.get_cgb_register(addr);
+    }
     if addr >= 0xff40 {
       return self.zero_page[(addr - 0xff00) as usize];
     }
     if addr == 0xff00 {
-      if self.key_select == KeySelect::Buttons {
-        return self.keys_buttons & 0xf;
-      } else {
-        return self.keys_directions & 0xf;
-      }
+      let player = self.active_player();
+      return match self.key_select {
+        KeySelect::Buttons => self.keys_buttons[player] & 0xf,
+        KeySelect::Directions => self.keys_directions[player] & 0xf,
+        // With neither line selected, SGB multiplayer reports the player ID
+        KeySelect::None => 0xf - (player as u8),
+      };
+    }
+    if addr == 0xff01 {
+      return self.serial.data;
+    }
+    if addr == 0xff26 {
+      return (self.zero_page[0x26] & 0x80) | 0x70 | self.audio.channel_status();
+    }
+    if addr >= 0xff10 && addr < 0xff30 {
+      // Write-only bits always read back as set
+      let mask = AUDIO_READ_MASKS[(addr - 0xff10) as usize];
+      return self.zero_page[(addr - 0xff00) as usize] | mask;
+    }
+    if addr >= 0xff30 && addr < 0xff40 {
+      return self.audio.channel_3.read_ram((addr - 0xff30) as usize, self.cgb_mode);
+    }
+    if addr == 0xff02 {
+      return self.serial.get_control(self.cgb_mode);
     }
     return self.zero_page[(addr - 0xff00) as usize];
   }
 
   pub fn set_byte(&mut self, addr: u16, value: u8) {
+    if self.dma_active && addr < 0xff00 {
+      if addr >= 0xfe00 || dma_bus(addr) == dma_bus(self.dma_source) {
+        // The bus is owned by the DMA unit, the write goes nowhere
+        return;
+      }
+    }
     if addr < 0x8000 {
       self.cart.write_rom_addr(addr, value);
       return;
     }
     if addr < 0xa000 {
-      self.video_ram[(addr - 0x8000) as usize] = value;
-      if addr < 0x9800 {
+      if !self.vram_accessible() {
+        return;
+      }
+      let index = self.vram_index(addr);
+      self.video_ram[index] = value;
+      if index >= 0x2000 {
+        // Bank 1 isn't mirrored to the host
+      } else if addr < 0x9800 {
         self.tile_data_dirty = true;
       } else if addr < 0x9c00 {
         self.tile_map_0_dirty = true;
@@ -173,14 +326,20 @@ impl MemMap {
       return;
     }
     if addr < 0xe000 {
-      self.work_ram[(addr - 0xc000) as usize] = value;
+      let index = self.wram_index(addr);
+      self.work_ram[index] = value;
       return;
     }
     if addr < 0xfe00 {
-      self.work_ram[(addr - 0xe000) as usize] = value;
+      let index = self.wram_index(addr - 0x2000);
+      self.work_ram[index] = value;
+      return;
     }
     if addr < 0xfea0 {
       // Sprite table
+      if !self.oam_accessible() {
+        return;
+      }
       self.sprite_table[(addr - 0xfe00) as usize] = value;
       return;
     }
@@ -208,26 +367,52 @@ impl MemMap {
         };
         self.zero_page[0x41] = (self.zero_page[0x41] & 0xfb) | coincidence;
       } else if addr == 0xff46 {
-        // DMA transfer
-        let src = (value as u16) << 8;
-        for i in 0..0x9f {
-          self.sprite_table[i as usize] = self.get_byte(src + i);
+        // DMA transfer, runs in the background over the next 160 M-cycles
+        self.zero_page[0x46] = value;
+        self.dma_active = true;
+        self.dma_pending = true;
+        self.dma_source = (value as u16) << 8;
+        self.dma_index = 0;
+        self.dma_time = 0;
+      } else if addr == 0xff4c {
+        // KEY0 is only writable by the CGB boot ROM, 0x04 selects DMG mode
+        if self.cgb_mode && self.zero_page[0x50] == 0 && value & 0x4 > 0 {
+          self.cgb_mode = false;
         }
+        self.zero_page[0x4c] = value;
+      } else if addr >= 0xff4d {
+        self.set_cgb_register(addr, value);
       } else {
         self.zero_page[(addr - 0xff00) as usize] = value;
       }
       return;
     }
     if addr == 0xff00 {
+      if self.sgb.enabled {
+        self.sgb.write_joypad(value);
+      }
       if value & 0b100000 == 0 {
         self.key_select = KeySelect::Buttons;
       } else if value & 0b10000 == 0 {
         self.key_select = KeySelect::Directions;
+      } else {
+        if self.key_select != KeySelect::None && self.sgb.enabled && self.sgb.player_count > 1 {
+          self.current_player = (self.current_player + 1) % self.sgb.player_count;
+        }
+        self.key_select = KeySelect::None;
       }
       return;
     }
+    if addr == 0xff01 {
+      self.serial.set_data(value);
+      return;
+    }
+    if addr == 0xff02 {
+      self.serial.set_control(value, self.cgb_mode);
+      return;
+    }
     if addr == 0xff04 {
-      self.zero_page[0x04] = 0;
+      self.reset_divider();
       return;
     }
     if addr == 0xff05 {
@@ -243,102 +428,480 @@ impl MemMap {
       return;
     }
 
+    if addr >= 0xff10 && addr < 0xff40 {
+      if let Some(ref mut log) = self.vgm {
+        log.write(addr, value);
+      }
+    }
+    if addr >= 0xff10 && addr < 0xff26 && !self.audio.enabled {
+      if self.cgb_mode || !is_length_register(addr) {
+        return;
+      }
+      // The DMG keeps its length counters writable while powered off
+      let value = if addr == 0xff1b { value } else { value & 0x3f };
+      match addr {
+        0xff11 => self.audio.channel_1.length.load(value),
+        0xff16 => self.audio.channel_2.length.load(value),
+        0xff1b => self.audio.channel_3.length.load(value),
+        _ => self.audio.channel_4.length.load(value),
+      }
+      return;
+    }
     if addr == 0xff10 {
+      // channel 1 sweep
+      self.audio.channel_1.set_sweep(value);
       self.zero_page[0x10] = value;
       return;
     }
-
-    if addr == 0xff14 {
-      if value & 0x80 > 0 {
-        // channel 1 enable
-        let sweep_time = (value & 0x70) >> 4;
-        let sweep_dir = (value & 0x8) >> 3;
-        let sweep_shift = value & 0x7;
-
-        let len = self.zero_page[0x11] & 0x3f;
-        let freq = (((value & 0x7) as u32) << 8) + (self.zero_page[0x13] as u32);
-        let nr12 = self.zero_page[0x12];
-        let vol = (nr12 & 0xf0) >> 4;
-        let vol_dir = (nr12 & 0x4) >> 3;
-        let vol_len = nr12 & 0x7;
-        self.audio.channel_1.sweep(sweep_time, sweep_dir, sweep_shift);
-        self.audio.channel_1.reset(len, freq, vol, vol_dir, vol_len);
-      }
-      self.zero_page[0x14] = value;
+    if addr == 0xff11 || addr == 0xff16 {
+      // duty and length
+      let channel = if addr == 0xff11 { &mut self.audio.channel_1 } else { &mut self.audio.channel_2 };
+      channel.set_duty_length(value);
+      self.zero_page[(addr - 0xff00) as usize] = value;
+      return;
+    }
+    if addr == 0xff12 || addr == 0xff17 {
+      // volume envelope
+      let channel = if addr == 0xff12 { &mut self.audio.channel_1 } else { &mut self.audio.channel_2 };
+      channel.set_dac(value);
+      self.zero_page[(addr - 0xff00) as usize] = value;
+      return;
+    }
+    if addr == 0xff13 || addr == 0xff18 {
+      let high = self.zero_page[(addr + 1 - 0xff00) as usize];
+      let channel = if addr == 0xff13 { &mut self.audio.channel_1 } else { &mut self.audio.channel_2 };
+      channel.set_freq((((high & 0x7) as u32) << 8) + (value as u32));
+      self.zero_page[(addr - 0xff00) as usize] = value;
+      return;
+    }
+    if addr == 0xff14 || addr == 0xff19 {
+      let low = self.zero_page[(addr - 1 - 0xff00) as usize];
+      let envelope = self.zero_page[(addr - 2 - 0xff00) as usize];
+      let channel = if addr == 0xff14 { &mut self.audio.channel_1 } else { &mut self.audio.channel_2 };
+      channel.set_freq((((value & 0x7) as u32) << 8) + (low as u32));
+      channel.control(value, envelope, self.audio.frame_step);
+      self.zero_page[(addr - 0xff00) as usize] = value & 0x47;
       return;
     }
 
-    if addr == 0xff19 {
-      if value & 0x80 > 0 {
-        // channel 2 enable
-        let len = self.zero_page[0x16] & 0x3f;
-        let freq = (((value & 0x7) as u32) << 8) + (self.zero_page[0x18] as u32);
-        let nr22 = self.zero_page[0x17];
-        let vol = (nr22 & 0xf0) >> 4;
-        let vol_dir = (nr22 & 0x4) >> 3;
-        let vol_len = nr22 & 0x7;
-        self.audio.channel_2.reset(len, freq, vol, vol_dir, vol_len);
-      }
-      self.zero_page[0x19] = value;
+    if addr == 0xff1a {
+      self.audio.channel_3.set_dac(value & 0x80 > 0);
+      self.zero_page[0x1a] = value & 0x80;
+      return;
+    }
+    if addr == 0xff1b {
+      // channel 3 length
+      self.audio.channel_3.length.load(value);
+      self.zero_page[0x1b] = value;
+      return;
+    }
+    if addr == 0xff1c {
+      // channel 3 output level
+      self.audio.channel_3.set_volume_code((value & 0x60) >> 5);
+      self.zero_page[0x1c] = value & 0x60;
+      return;
+    }
+    if addr == 0xff1d {
+      let freq = (((self.zero_page[0x1e] & 0x7) as u32) << 8) + (value as u32);
+      self.audio.channel_3.set_freq(freq);
+      self.zero_page[0x1d] = value;
+      return;
+    }
+    if addr == 0xff1e {
+      let freq = (((value & 0x7) as u32) << 8) + (self.zero_page[0x1d] as u32);
+      self.audio.channel_3.set_freq(freq);
+      let frame_step = self.audio.frame_step;
+      self.audio.channel_3.control(value, frame_step);
+      self.zero_page[0x1e] = value & 0x47;
+      return;
+    }
+    if addr >= 0xff30 && addr < 0xff40 {
+      // wave pattern RAM
+      self.audio.channel_3.write_ram((addr - 0xff30) as usize, value, self.cgb_mode);
       return;
     }
 
     if addr == 0xff20 {
       // channel 4 length
+      self.audio.channel_4.length.load(value);
       self.zero_page[0x20] = value & 0x3f;
       return;
     }
     if addr == 0xff21 {
+      self.audio.channel_4.set_dac(value);
       self.zero_page[0x21] = value;
       return;
     }
     if addr == 0xff22 {
       // channel 4 polynomial counter
+      self.audio.channel_4.set_polynomial(value);
+      self.zero_page[0x22] = value;
       return;
     }
     if addr == 0xff23 {
-      if value & 0x80 > 0 {
-        // channel 4 enable
-        let len = self.zero_page[0x20];
-        let nr42 = self.zero_page[0x21];
-        let vol = (nr42 & 0xf0) >> 4;
-        let vol_dir = (nr42 & 0x4) >> 3;
-        let vol_len = nr42 & 0x7;
-        self.audio.channel_4.reset(len, vol, vol_dir, vol_len);
-      }
-      self.zero_page[0x23] = value & 0xc0;
+      let frame_step = self.audio.frame_step;
+      self.audio.channel_4.control(value, self.zero_page[0x21], frame_step);
+      self.zero_page[0x23] = value & 0x40;
       return;
     }
     if addr == 0xff24 {
       // master volume
-      let left = (value & 0x70) >> 4;
-      let right = value & 0x7;
-      unsafe { set_master_gain(left, right); }
+      self.audio.master_left = (value & 0x70) >> 4;
+      self.audio.master_right = value & 0x7;
+      self.audio.vin_left = value & 0x80 > 0;
+      self.audio.vin_right = value & 0x08 > 0;
+      self.zero_page[0x24] = value;
       return;
     }
     if addr == 0xff25 {
       // sound to terminal
-      //unsafe { set_sound_to_terminals(value); }
+      self.audio.panning = value;
       self.zero_page[0x25] = value;
       return;
     }
     if addr == 0xff26 {
       // sound on/off
-      let on_off = value & 0x80;
-      if on_off > 0 {
-        // enable sound
-        self.zero_page[0x26] &= 0x80;
-        unsafe { audio_enabled(1); }
-      } else {
+      if value & 0x80 > 0 {
+        self.zero_page[0x26] = 0x80;
+        self.audio.enabled = true;
+      } else if self.audio.enabled {
+        // Powering off clears every register but wave RAM
+        for reg in 0x10..0x26 {
+          self.zero_page[reg] = 0;
+        }
         self.zero_page[0x26] = 0;
-        unsafe { audio_enabled(0); }
+        self.audio.power_off(self.cgb_mode);
       }
       return;
     }
+    if addr >= 0xff27 && addr < 0xff30 {
+      // Unused
+      return;
+    }
 
     self.zero_page[(addr - 0xff00) as usize] = value;
   }
 
+  fn dma_read(&self, addr: u16) -> u8 {
+    // The DMA unit bypasses the CPU's view of memory
+    if addr < 0x8000 {
+      return self.cart.get_rom_byte(addr);
+    }
+    if addr < 0xa000 {
+      return self.video_ram[self.vram_index(addr)];
+    }
+    if addr < 0xc000 {
+      return self.cart.get_ram_byte(addr - 0xa000);
+    }
+    if addr < 0xe000 {
+      return self.work_ram[self.wram_index(addr)];
+    }
+    // Sources at 0xe000 and above all read from echo RAM
+    return self.work_ram[self.wram_index(0xc000 + ((addr - 0xe000) & 0x1fff))];
+  }
+
+  fn get_byte_during_dma(&self, addr: u16) -> u8 {
+    if addr >= 0xfe00 {
+      // OAM is being written by the DMA unit
+      return 0xff;
+    }
+    if dma_bus(addr) == dma_bus(self.dma_source) {
+      // Bus conflict, the CPU sees whatever the DMA unit is reading
+      let index = if self.dma_index < 0xa0 { self.dma_index } else { 0x9f };
+      return self.dma_read(self.dma_source + index);
+    }
+    // The other bus is still free
+    return self.get_byte_unrestricted(addr);
+  }
+
+  fn get_byte_unrestricted(&self, addr: u16) -> u8 {
+    if self.is_boot_rom_mapped(addr) {
+      return self.boot[addr as usize];
+    }
+    if addr < 0x8000 {
+      return self.cart.get_rom_byte(addr);
+    }
+    if addr < 0xa000 {
+      if !self.vram_accessible() {
+        return 0xff;
+      }
+      return self.video_ram[self.vram_index(addr)];
+    }
+    if addr < 0xc000 {
+      return self.cart.get_ram_byte(addr - 0xa000);
+    }
+    return self.dma_read(addr);
+  }
+
+  fn advance_dma(&mut self, time: u8) {
+    if self.dma_pending {
+      // The rest of the triggering instruction doesn't count towards the transfer
+      self.dma_pending = false;
+      return;
+    }
+    self.dma_time += time as u16;
+    // One M-cycle of setup, followed by one byte per M-cycle
+    while self.dma_active && self.dma_time >= (self.dma_index + 2) * 4 {
+      let byte = self.dma_read(self.dma_source + self.dma_index);
+      self.sprite_table[self.dma_index as usize] = byte;
+      self.dma_index += 1;
+      if self.dma_index >= 0xa0 {
+        self.dma_active = false;
+      }
+    }
+  }
+
+  pub fn is_dma_active(&self) -> bool {
+    return self.dma_active;
+  }
+
+  fn vram_index(&self, addr: u16) -> usize {
+    return (self.vram_bank as usize) * 0x2000 + ((addr - 0x8000) as usize);
+  }
+
+  fn wram_index(&self, addr: u16) -> usize {
+    // 0xc000-0xcfff is always bank 0, 0xd000-0xdfff is switchable
+    let offset = (addr - 0xc000) as usize;
+    if offset < 0x1000 {
+      return offset;
+    }
+    return (self.wram_bank as usize) * 0x1000 + offset - 0x1000;
+  }
+
+  pub fn set_cgb_mode(&mut self, enabled: bool) {
+    self.cgb_mode = enabled;
+    self.vram_bank = 0;
+    self.wram_bank = 1;
+    self.zero_page[0x4d] = 0;
+    self.hdma_blocks = 0;
+    self.hdma_active = false;
+  }
+
+  pub fn is_double_speed(&self) -> bool {
+    return self.zero_page[0x4d] & 0x80 > 0;
+  }
+
+  pub fn is_speed_switch_armed(&self) -> bool {
+    return self.cgb_mode && self.zero_page[0x4d] & 0x1 > 0;
+  }
+
+  pub fn switch_speed(&mut self) {
+    self.zero_page[0x4d] = (self.zero_page[0x4d] ^ 0x80) & 0x80;
+  }
+
+  // Called when STOP executes with KEY1 armed. The CPU sits out about 2050
+  // M-cycles while the clock settles, and DIV starts over.
+  pub fn stop_for_speed_switch(&mut self) {
+    self.switch_speed();
+    self.reset_divider();
+    self.cpu_stall += SPEED_SWITCH_STALL;
+  }
+
+  fn reset_divider(&mut self) {
+    if self.zero_page[0x04] & self.frame_sequencer_bit() > 0 {
+      // Resetting DIV can make the bit the APU watches fall
+      self.audio.clock_frame_sequencer();
+    }
+    self.zero_page[0x04] = 0;
+  }
+
+  fn get_cgb_register(&self, addr: u16) -> u8 {
+    let is_register = match addr {
+      0xff4d | 0xff4f | 0xff51..=0xff55 | 0xff68..=0xff6b | 0xff70 => true,
+      _ => false,
+    };
+    if !is_register {
+      return self.zero_page[(addr - 0xff00) as usize];
+    }
+    if !self.cgb_mode {
+      return 0xff;
+    }
+    return match addr {
+      0xff4d => 0x7e | self.zero_page[0x4d],
+      0xff4f => 0xfe | self.vram_bank,
+      0xff55 => {
+        if self.hdma_active {
+          (self.hdma_blocks - 1) & 0x7f
+        } else if self.hdma_blocks > 0 {
+          // Cancelled H-blank DMA reports what was left
+          0x80 | ((self.hdma_blocks - 1) & 0x7f)
+        } else {
+          0xff
+        }
+      },
+      0xff51..=0xff54 => 0xff,
+      0xff68 => 0x40 | self.zero_page[0x68],
+      0xff69 => {
+        if !self.vram_accessible() {
+          0xff
+        } else {
+          self.bg_palette_ram[(self.zero_page[0x68] & 0x3f) as usize]
+        }
+      },
+      0xff6a => 0x40 | self.zero_page[0x6a],
+      0xff6b => {
+        if !self.vram_accessible() {
+          0xff
+        } else {
+          self.obj_palette_ram[(self.zero_page[0x6a] & 0x3f) as usize]
+        }
+      },
+      _ => 0xf8 | self.wram_bank, // 0xff70
+    };
+  }
+
+  fn set_cgb_register(&mut self, addr: u16, value: u8) {
+    let is_register = match addr {
+      0xff4d | 0xff4f | 0xff51..=0xff55 | 0xff68..=0xff6b | 0xff70 => true,
+      _ => false,
+    };
+    if !is_register {
+      self.zero_page[(addr - 0xff00) as usize] = value;
+      return;
+    }
+    if !self.cgb_mode {
+      return;
+    }
+    match addr {
+      0xff4d => {
+        // Speed switch preparation, the current speed lives in bit 7
+        self.zero_page[0x4d] = (self.zero_page[0x4d] & 0x80) | (value & 0x1);
+      },
+      0xff4f => self.vram_bank = value & 0x1,
+      0xff51 => self.hdma_source = (self.hdma_source & 0x00ff) | ((value as u16) << 8),
+      0xff52 => self.hdma_source = (self.hdma_source & 0xff00) | ((value & 0xf0) as u16),
+      0xff53 => self.hdma_dest = (self.hdma_dest & 0x00ff) | (((value & 0x1f) as u16) << 8),
+      0xff54 => self.hdma_dest = (self.hdma_dest & 0xff00) | ((value & 0xf0) as u16),
+      0xff55 => self.start_vram_dma(value),
+      0xff68 => self.zero_page[0x68] = value & 0xbf,
+      0xff69 => {
+        let spec = self.zero_page[0x68];
+        if self.vram_accessible() {
+          self.bg_palette_ram[(spec & 0x3f) as usize] = value;
+        }
+        self.zero_page[0x68] = next_palette_spec(spec);
+      },
+      0xff6a => self.zero_page[0x6a] = value & 0xbf,
+      0xff6b => {
+        let spec = self.zero_page[0x6a];
+        if self.vram_accessible() {
+          self.obj_palette_ram[(spec & 0x3f) as usize] = value;
+        }
+        self.zero_page[0x6a] = next_palette_spec(spec);
+      },
+      _ => {
+        // 0xff70, bank 0 selects bank 1
+        let bank = value & 0x7;
+        self.wram_bank = if bank == 0 { 1 } else { bank };
+      },
+    };
+  }
+
+  fn start_vram_dma(&mut self, value: u8) {
+    if self.hdma_active && value & 0x80 == 0 {
+      // Writing bit 7 low cancels an H-blank transfer
+      self.hdma_active = false;
+      return;
+    }
+    self.hdma_blocks = (value & 0x7f) + 1;
+    if value & 0x80 == 0 {
+      // General purpose DMA copies everything at once, halting the CPU
+      while self.hdma_blocks > 0 {
+        self.copy_vram_dma_block();
+      }
+      return;
+    }
+    self.hdma_active = true;
+    if self.zero_page[0x40] & 0x80 == 0 {
+      // With the LCD off, the first block is copied right away
+      self.copy_vram_dma_block();
+    }
+  }
+
+  // Called by the GPU each time it enters H-blank
+  pub fn hblank_dma(&mut self) {
+    if !self.hdma_active || self.zero_page[0x40] & 0x80 == 0 {
+      return;
+    }
+    self.copy_vram_dma_block();
+  }
+
+  fn copy_vram_dma_block(&mut self) {
+    for _ in 0..0x10 {
+      let byte = self.dma_read(self.hdma_source);
+      let dest = 0x8000 + (self.hdma_dest & 0x1fff);
+      let index = self.vram_index(dest);
+      self.video_ram[index] = byte;
+      if index < 0x1800 {
+        self.tile_data_dirty = true;
+      } else if index < 0x1c00 {
+        self.tile_map_0_dirty = true;
+      } else if index < 0x2000 {
+        self.tile_map_1_dirty = true;
+      }
+      self.hdma_source = self.hdma_source.wrapping_add(1);
+      self.hdma_dest = (self.hdma_dest + 1) & 0x1fff;
+    }
+    // 8 M-cycles per block at normal speed, 16 in double speed
+    self.cpu_stall += if self.is_double_speed() { 64 } else { 32 };
+    self.hdma_blocks -= 1;
+    if self.hdma_blocks == 0 {
+      self.hdma_active = false;
+    }
+  }
+
+  // Returns the number of cycles the CPU should spend stalled, up to one M-cycle
+  pub fn consume_cpu_stall(&mut self) -> u8 {
+    if self.cpu_stall == 0 {
+      return 0;
+    }
+    let cycles = if self.cpu_stall < 4 { self.cpu_stall } else { 4 };
+    self.cpu_stall -= cycles;
+    return cycles as u8;
+  }
+
+  // Called at the start of vblank. SGB transfer commands read the 4KB of
+  // tile data shown in the first 256 tiles of the background.
+  pub fn sgb_vblank(&mut self, screen: &[u8]) {
+    if !self.sgb.enabled {
+      return;
+    }
+    if self.sgb.has_pending_transfer() {
+      let control = self.zero_page[0x40];
+      let map = if control & 0x8 > 0 { 0x1c00 } else { 0x1800 };
+      let mut data = vec![0; 0x1000];
+      for i in 0..256 {
+        let tile = self.video_ram[map + (i / 20) * 32 + i % 20] as usize;
+        let tile_addr = if control & 0x10 > 0 {
+          tile * 16
+        } else {
+          (0x1000 + ((tile as i8 as isize) * 16)) as usize
+        };
+        data[i * 16..i * 16 + 16].copy_from_slice(&self.video_ram[tile_addr..tile_addr + 16]);
+      }
+      self.sgb.complete_transfer(&data);
+    }
+    self.sgb.render(screen);
+  }
+
+  fn lcd_mode(&self) -> u8 {
+    if self.zero_page[0x40] & 0x80 == 0 {
+      // LCD is off, the PPU isn't touching memory
+      return 0;
+    }
+    return self.zero_page[0x41] & 0x3;
+  }
+
+  fn vram_accessible(&self) -> bool {
+    // VRAM is locked while pixels are transferred in mode 3
+    return !self.restrict_vram_access || self.lcd_mode() != 3;
+  }
+
+  fn oam_accessible(&self) -> bool {
+    // OAM is locked during the OAM search (mode 2) and pixel transfer (mode 3)
+    return !self.restrict_vram_access || self.lcd_mode() < 2;
+  }
+
   pub fn get_word(&self, addr: u16) -> u16 {
     let low = self.get_byte(addr) as u16;
     let high = self.get_byte(addr + 1) as u16;
@@ -386,35 +949,50 @@ impl MemMap {
   }
 
   pub fn key_down_button(&mut self, mask: u8) {
-    self.keys_buttons = self.keys_buttons & mask;
+    self.keys_buttons[0] = self.keys_buttons[0] & mask;
     self.zero_page[0x0f] = self.zero_page[0x0f] | 0x10;
   }
 
   pub fn key_up_button(&mut self, mask: u8) {
-    self.keys_buttons = self.keys_buttons | mask;
+    self.keys_buttons[0] = self.keys_buttons[0] | mask;
     self.zero_page[0x0f] = self.zero_page[0x0f] | 0x10;
   }
 
   pub fn key_down_direction(&mut self, mask: u8) {
-    self.keys_directions = self.keys_directions & mask;
+    self.keys_directions[0] = self.keys_directions[0] & mask;
     self.zero_page[0x0f] = self.zero_page[0x0f] | 0x10;
   }
 
   pub fn key_up_direction(&mut self, mask: u8) {
-    self.keys_directions = self.keys_directions | mask;
+    self.keys_directions[0] = self.keys_directions[0] | mask;
     self.zero_page[0x0f] = self.zero_page[0x0f] | 0x10;
   }
 
   pub fn set_buttons(&mut self, buttons: u8) {
-    self.keys_buttons = buttons;
-    self.zero_page[0x0f] = self.zero_page[0x0f] | 0x10;
+    self.set_player_buttons(0, buttons);
   }
 
   pub fn set_directions(&mut self, directions: u8) {
-    self.keys_directions = directions;
+    self.set_player_directions(0, directions);
+  }
+
+  pub fn set_player_buttons(&mut self, player: u8, buttons: u8) {
+    self.keys_buttons[(player & 0x3) as usize] = buttons;
     self.zero_page[0x0f] = self.zero_page[0x0f] | 0x10;
   }
 
+  pub fn set_player_directions(&mut self, player: u8, directions: u8) {
+    self.keys_directions[(player & 0x3) as usize] = directions;
+    self.zero_page[0x0f] = self.zero_page[0x0f] | 0x10;
+  }
+
+  fn active_player(&self) -> usize {
+    if self.sgb.enabled && self.sgb.player_count > 1 {
+      return (self.current_player % self.sgb.player_count) as usize;
+    }
+    return 0;
+  }
+
   pub fn add_time(&mut self, time: u8) {
     let next_time = self.timer + (time as u16);
     let base_start = self.timer / 16;
@@ -422,7 +1000,13 @@ impl MemMap {
     if base_end > base_start {
       if base_end / 16 > base_start / 16 {
         // Increment divider
-        self.zero_page[0x04] += 1;
+        let div = self.zero_page[0x04];
+        let next_div = div.wrapping_add(1);
+        let bit = self.frame_sequencer_bit();
+        if div & bit > 0 && next_div & bit == 0 {
+          self.audio.clock_frame_sequencer();
+        }
+        self.zero_page[0x04] = next_div;
       }
       let control = self.zero_page[0x07];
       if control & 0x4 > 0 {
@@ -451,7 +1035,52 @@ impl MemMap {
       self.timer = next_time;
     }
 
-    self.audio.add_time(time);
+    if self.dma_active {
+      self.advance_dma(time);
+    }
+
+    if self.serial.add_time(time) {
+      self.zero_page[0x0f] |= 8;
+    }
+
+    // The APU runs in real time, regardless of the CPU speed
+    let audio_time = if self.is_double_speed() { time / 2 } else { time };
+    self.audio.add_time(audio_time);
+    if let Some(ref mut log) = self.vgm {
+      log.add_time(audio_time);
+    }
+  }
+
+  // Starts logging APU writes, beginning with the current register state.
+  // Channels that are sounding get triggered again so playback starts with
+  // them on, though their envelopes, sweeps and lengths restart from the
+  // register values.
+  pub fn start_vgm_log(&mut self) {
+    let mut log = vgm::create_vgm_logger();
+    log.write(0xff26, self.zero_page[0x26] & 0x80);
+    for i in 0..16 {
+      log.write(0xff30 + i as u16, self.audio.channel_3.ram[i]);
+    }
+    for addr in 0xff10..0xff26 {
+      log.write(addr, self.zero_page[(addr - 0xff00) as usize]);
+    }
+    let status = self.audio.channel_status();
+    for (channel, addr) in [0xff14, 0xff19, 0xff1e, 0xff23].iter().enumerate() {
+      if status & (1 << channel) > 0 {
+        log.write(*addr, self.zero_page[(*addr - 0xff00) as usize] | 0x80);
+      }
+    }
+    self.vgm = Some(log);
+  }
+
+  // Returns the finished VGM file, if logging was started
+  pub fn stop_vgm_log(&mut self) -> Option<Vec<u8>> {
+    return self.vgm.take().map(|log| log.finish());
+  }
+
+  // DIV bit whose falling edge clocks the APU frame sequencer at 512 Hz
+  fn frame_sequencer_bit(&self) -> u8 {
+    return if self.is_double_speed() { 0x20 } else { 0x10 };
   }
 
   pub fn is_cart_ram_dirty(&mut self) -> bool {
@@ -479,9 +1108,27 @@ impl MemMap {
   }
 }
 
+// Palette index registers auto-increment after writes when bit 7 is set
+fn next_palette_spec(spec: u8) -> u8 {
+  if spec & 0x80 == 0 {
+    return spec;
+  }
+  return 0x80 | ((spec + 1) & 0x3f);
+}
+
+// The external bus serves the cartridge and work RAM, the video bus serves VRAM
+fn dma_bus(addr: u16) -> u8 {
+  if addr >= 0x8000 && addr < 0xa000 {
+    return 1;
+  }
+  return 0;
+}
+
 #[cfg(test)]
 mod tests {
   use vm::memmap::create_memmap;
+  use vm::random::create_rng;
+  use vm::random::RamInit;
 
   #[test]
   fn get_word() {
@@ -499,6 +1146,106 @@ mod tests {
     assert_eq!(mem.get_byte(0x9046), 0xfa);
   }
 
+  #[test]
+  fn wave_registers() {
+    let mut mem = create_memmap(0);
+    mem.set_byte(0xff26, 0x80);
+    mem.set_byte(0xff30, 0xab);
+    assert_eq!(mem.get_byte(0xff30), 0xab);
+    // Triggering with the DAC off leaves the channel silent
+    mem.set_byte(0xff1e, 0x80);
+    assert!(!mem.audio.channel_3.enabled);
+    mem.set_byte(0xff1a, 0x80);
+    mem.set_byte(0xff1c, 0x20);
+    mem.set_byte(0xff1e, 0x87);
+    assert!(mem.audio.channel_3.enabled);
+    assert_eq!(mem.get_byte(0xff30), 0xff);
+    mem.set_byte(0xff1a, 0);
+    assert!(!mem.audio.channel_3.enabled);
+    assert_eq!(mem.get_byte(0xff30), 0xab);
+  }
+
+  #[test]
+  fn divider_clocks_frame_sequencer() {
+    let mut mem = create_memmap(0);
+    mem.set_byte(0xff26, 0x80);
+    assert_eq!(mem.audio.frame_step, 0);
+    // DIV bit 4 falls every 8192 cycles
+    for _ in 0..(8192 / 4) {
+      mem.add_time(4);
+    }
+    assert_eq!(mem.audio.frame_step, 1);
+    for _ in 0..(4096 / 4) {
+      mem.add_time(4);
+    }
+    // Resetting DIV with the bit set also clocks it
+    mem.set_byte(0xff04, 0);
+    assert_eq!(mem.audio.frame_step, 2);
+    mem.set_byte(0xff04, 0);
+    assert_eq!(mem.audio.frame_step, 2);
+  }
+
+  #[test]
+  fn audio_status_and_power() {
+    let mut mem = create_memmap(0);
+    // Registers are ignored while powered off
+    mem.set_byte(0xff12, 0xf0);
+    assert_eq!(mem.get_byte(0xff12), 0x00);
+    assert_eq!(mem.get_byte(0xff26), 0x70);
+
+    mem.set_byte(0xff26, 0x80);
+    mem.set_byte(0xff12, 0xf0);
+    mem.set_byte(0xff11, 0x80);
+    mem.set_byte(0xff14, 0x80);
+    mem.set_byte(0xff25, 0x11);
+    mem.set_byte(0xff30, 0x12);
+    assert_eq!(mem.get_byte(0xff26), 0xf1);
+    // Only the duty, envelope and length enable bits read back
+    assert_eq!(mem.get_byte(0xff11), 0xbf);
+    assert_eq!(mem.get_byte(0xff12), 0xf0);
+    assert_eq!(mem.get_byte(0xff13), 0xff);
+    assert_eq!(mem.get_byte(0xff14), 0xbf);
+    assert_eq!(mem.get_byte(0xff25), 0x11);
+    assert_eq!(mem.get_byte(0xff27), 0xff);
+    assert_eq!(mem.audio.panning, 0x11);
+
+    mem.set_byte(0xff26, 0x00);
+    assert_eq!(mem.get_byte(0xff26), 0x70);
+    assert_eq!(mem.get_byte(0xff12), 0x00);
+    assert_eq!(mem.get_byte(0xff25), 0x00);
+    assert!(!mem.audio.channel_1.enabled);
+    assert_eq!(mem.get_byte(0xff30), 0x12);
+    // DMG length counters can still be written
+    mem.set_byte(0xff11, 0xff);
+    assert_eq!(mem.audio.channel_1.length.counter, 1);
+    assert_eq!(mem.get_byte(0xff11), 0x3f);
+  }
+
+  #[test]
+  fn logs_apu_writes() {
+    let mut mem = create_memmap(0);
+    mem.set_byte(0xff26, 0x80);
+    mem.set_byte(0xff25, 0x33);
+    // Channel 2 is sounding when logging starts
+    mem.set_byte(0xff17, 0xf0);
+    mem.set_byte(0xff19, 0xc7);
+    mem.start_vgm_log();
+    mem.add_time(100);
+    mem.set_byte(0xff24, 0x77);
+    mem.set_byte(0xff40, 0x91);
+    let vgm = mem.stop_vgm_log().unwrap();
+    assert!(mem.vgm.is_none());
+    // NR52, wave RAM and 0xff10-0xff25 describe the starting state
+    let commands = &vgm[0x100..];
+    assert_eq!(&commands[0..3], &[0xb3, 0x16, 0x80]);
+    assert_eq!(commands.len(), (1 + 16 + 22 + 1 + 1) * 3 + 1 + 1);
+    assert_eq!(&commands[(1 + 16 + 21) * 3..(1 + 16 + 22) * 3], &[0xb3, 0x15, 0x33]);
+    // Then a trigger for channel 2, with its length enable and frequency
+    assert_eq!(&commands[(1 + 16 + 22) * 3..(1 + 16 + 23) * 3], &[0xb3, 0x09, 0xc7]);
+    // One sample later, the NR50 write
+    assert_eq!(&commands[commands.len() - 5..], &[0x70, 0xb3, 0x14, 0x77, 0x66]);
+  }
+
   #[test]
   fn divider() {
     let mut mem = create_memmap(0);
@@ -572,4 +1319,356 @@ mod tests {
     // resets to modulo
     assert_eq!(mem.get_byte(0xff05), 7);
   }
+
+  #[test]
+  fn dma_copies_all_sprites() {
+    let mut mem = create_memmap(0);
+    for i in 0..0xa0 {
+      mem.set_byte(0xc000 + i, (i as u8) + 1);
+    }
+    mem.set_byte(0xff46, 0xc0);
+    // The triggering instruction
+    mem.add_time(12);
+    assert_eq!(mem.sprite_table[0], 0);
+    for _ in 0..80 {
+      mem.add_time(4);
+    }
+    assert_eq!(mem.sprite_table[78], 79);
+    assert_eq!(mem.sprite_table[79], 0);
+    assert!(mem.is_dma_active());
+    for _ in 0..81 {
+      mem.add_time(4);
+    }
+    assert!(!mem.is_dma_active());
+    assert_eq!(mem.sprite_table[0], 1);
+    assert_eq!(mem.sprite_table[0x9f], 0xa0);
+    assert_eq!(mem.get_byte(0xfe9f), 0xa0);
+    assert_eq!(mem.get_byte(0xff46), 0xc0);
+  }
+
+  #[test]
+  fn dma_bus_conflicts() {
+    let mut mem = create_memmap(0);
+    mem.set_byte(0xc002, 0x55);
+    mem.set_byte(0xd000, 0x66);
+    mem.set_byte(0x8000, 0x77);
+    mem.set_byte(0xff80, 0x88);
+    mem.set_byte(0xff46, 0xc0);
+    mem.add_time(12);
+    mem.add_time(12);
+    // Reading the external bus returns the byte being transferred
+    assert_eq!(mem.get_byte(0xd000), 0x55);
+    assert_eq!(mem.get_byte(0x0150), 0x55);
+    // HRAM and the video bus are unaffected, OAM is unreadable
+    assert_eq!(mem.get_byte(0xff80), 0x88);
+    assert_eq!(mem.get_byte(0x8000), 0x77);
+    assert_eq!(mem.get_byte(0xfe00), 0xff);
+    mem.set_byte(0xd000, 0x11);
+    mem.set_byte(0xff81, 0x22);
+    for _ in 0..160 {
+      mem.add_time(4);
+    }
+    assert_eq!(mem.get_byte(0xd000), 0x66);
+    assert_eq!(mem.get_byte(0xff81), 0x22);
+  }
+
+  #[test]
+  fn dma_from_echo_ram() {
+    let mut mem = create_memmap(0);
+    mem.set_byte(0xc09f, 0x42);
+    mem.set_byte(0xde9f, 0x24);
+    mem.set_byte(0xff46, 0xe0);
+    mem.add_time(4);
+    for _ in 0..161 {
+      mem.add_time(4);
+    }
+    assert_eq!(mem.sprite_table[0x9f], 0x42);
+    mem.set_byte(0xff46, 0xfe);
+    mem.add_time(4);
+    for _ in 0..161 {
+      mem.add_time(4);
+    }
+    assert_eq!(mem.sprite_table[0x9f], 0x24);
+  }
+
+  #[test]
+  fn cgb_vram_banks() {
+    let mut mem = create_memmap(0);
+    mem.set_byte(0xff4f, 1);
+    mem.set_byte(0x8000, 0x11);
+    assert_eq!(mem.get_byte(0x8000), 0x11);
+    assert_eq!(mem.get_byte(0xff4f), 0xff);
+    mem.set_cgb_mode(true);
+    mem.set_byte(0xff4f, 1);
+    assert_eq!(mem.get_byte(0xff4f), 0xff);
+    mem.set_byte(0x8000, 0x22);
+    assert_eq!(mem.get_byte(0x8000), 0x22);
+    assert_eq!(mem.video_ram[0x2000], 0x22);
+    mem.set_byte(0xff4f, 0);
+    assert_eq!(mem.get_byte(0xff4f), 0xfe);
+    assert_eq!(mem.get_byte(0x8000), 0x11);
+  }
+
+  #[test]
+  fn cgb_wram_banks() {
+    let mut mem = create_memmap(0);
+    mem.set_cgb_mode(true);
+    mem.set_byte(0xc000, 0x10);
+    mem.set_byte(0xd000, 0x01);
+    mem.set_byte(0xff70, 0);
+    assert_eq!(mem.get_byte(0xff70), 0xf9);
+    assert_eq!(mem.get_byte(0xd000), 0x01);
+    mem.set_byte(0xff70, 7);
+    mem.set_byte(0xd000, 0x07);
+    assert_eq!(mem.work_ram[0x7000], 0x07);
+    assert_eq!(mem.get_byte(0xf000), 0x07);
+    assert_eq!(mem.get_byte(0xc000), 0x10);
+    mem.set_byte(0xff70, 1);
+    assert_eq!(mem.get_byte(0xd000), 0x01);
+  }
+
+  #[test]
+  fn cgb_palette_ram() {
+    let mut mem = create_memmap(0);
+    mem.set_cgb_mode(true);
+    mem.set_byte(0xff68, 0x80 | 0x3e);
+    mem.set_byte(0xff69, 0x1f);
+    mem.set_byte(0xff69, 0x7c);
+    // Auto-increment wraps around
+    mem.set_byte(0xff69, 0x55);
+    assert_eq!(mem.bg_palette_ram[0x3e], 0x1f);
+    assert_eq!(mem.bg_palette_ram[0x3f], 0x7c);
+    assert_eq!(mem.bg_palette_ram[0x00], 0x55);
+    assert_eq!(mem.get_byte(0xff68), 0xc1);
+    mem.set_byte(0xff6a, 0x02);
+    mem.set_byte(0xff6b, 0x33);
+    mem.set_byte(0xff6b, 0x44);
+    assert_eq!(mem.get_byte(0xff6a), 0x42);
+    assert_eq!(mem.get_byte(0xff6b), 0x44);
+    assert_eq!(mem.obj_palette_ram[2], 0x44);
+  }
+
+  #[test]
+  fn cgb_speed_switch() {
+    let mut mem = create_memmap(0);
+    mem.set_byte(0xff4d, 1);
+    assert!(!mem.is_speed_switch_armed());
+    assert_eq!(mem.get_byte(0xff4d), 0xff);
+    mem.set_cgb_mode(true);
+    assert_eq!(mem.get_byte(0xff4d), 0x7e);
+    mem.set_byte(0xff4d, 1);
+    assert!(mem.is_speed_switch_armed());
+    assert_eq!(mem.get_byte(0xff4d), 0x7f);
+    for _ in 0..64 {
+      mem.add_time(4);
+    }
+    assert_eq!(mem.get_byte(0xff04), 1);
+    mem.stop_for_speed_switch();
+    assert!(mem.is_double_speed());
+    assert!(!mem.is_speed_switch_armed());
+    assert_eq!(mem.get_byte(0xff4d), 0xfe);
+    // DIV starts over and the CPU stalls while the clock settles
+    assert_eq!(mem.get_byte(0xff04), 0);
+    let mut stall = 0;
+    loop {
+      let cycles = mem.consume_cpu_stall();
+      if cycles == 0 {
+        break;
+      }
+      stall += cycles as u32;
+    }
+    assert_eq!(stall, 2050 * 4);
+    // Writes can't change the current speed
+    mem.set_byte(0xff4d, 0);
+    assert!(mem.is_double_speed());
+    // Timers keep running from the CPU clock
+    for _ in 0..64 {
+      mem.add_time(4);
+    }
+    assert_eq!(mem.get_byte(0xff04), 1);
+  }
+
+  #[test]
+  fn cgb_general_purpose_dma() {
+    let mut mem = create_memmap(0);
+    mem.set_cgb_mode(true);
+    for i in 0..0x20 {
+      mem.set_byte(0xc100 + i, i as u8 + 1);
+    }
+    mem.set_byte(0xff4f, 1);
+    mem.set_byte(0xff51, 0xc1);
+    mem.set_byte(0xff52, 0x00);
+    mem.set_byte(0xff53, 0x88);
+    mem.set_byte(0xff54, 0x10);
+    mem.set_byte(0xff55, 0x01);
+    assert_eq!(mem.video_ram[0x2810], 1);
+    assert_eq!(mem.video_ram[0x282f], 0x20);
+    assert_eq!(mem.get_byte(0xff55), 0xff);
+    let mut stall = 0;
+    loop {
+      let cycles = mem.consume_cpu_stall();
+      if cycles == 0 {
+        break;
+      }
+      stall += cycles as u32;
+    }
+    assert_eq!(stall, 64);
+  }
+
+  #[test]
+  fn cgb_hblank_dma() {
+    let mut mem = create_memmap(0);
+    mem.set_cgb_mode(true);
+    mem.zero_page[0x40] = 0x80;
+    for i in 0..0x30 {
+      mem.set_byte(0xd000 + i, 0x80 | i as u8);
+    }
+    mem.set_byte(0xff51, 0xd0);
+    mem.set_byte(0xff52, 0x00);
+    mem.set_byte(0xff53, 0x00);
+    mem.set_byte(0xff54, 0x00);
+    mem.set_byte(0xff55, 0x82);
+    assert_eq!(mem.video_ram[0], 0);
+    assert_eq!(mem.get_byte(0xff55), 0x02);
+    mem.hblank_dma();
+    assert_eq!(mem.video_ram[0x0f], 0x8f);
+    assert_eq!(mem.video_ram[0x10], 0);
+    assert_eq!(mem.get_byte(0xff55), 0x01);
+    // Cancel with one block left
+    mem.hblank_dma();
+    mem.set_byte(0xff55, 0x00);
+    assert_eq!(mem.get_byte(0xff55), 0x80);
+    mem.hblank_dma();
+    assert_eq!(mem.video_ram[0x1f], 0x9f);
+    assert_eq!(mem.video_ram[0x20], 0);
+  }
+
+  #[test]
+  fn vram_locked_in_mode_3() {
+    let mut mem = create_memmap(0);
+    mem.set_byte(0x8010, 0x12);
+    mem.zero_page[0x40] = 0x80;
+    mem.zero_page[0x41] = 0x2;
+    assert_eq!(mem.get_byte(0x8010), 0x12);
+    mem.zero_page[0x41] = 0x3;
+    assert_eq!(mem.get_byte(0x8010), 0xff);
+    mem.set_byte(0x8010, 0x34);
+    mem.zero_page[0x41] = 0x0;
+    assert_eq!(mem.get_byte(0x8010), 0x12);
+    mem.set_byte(0x8010, 0x34);
+    assert_eq!(mem.get_byte(0x8010), 0x34);
+  }
+
+  #[test]
+  fn oam_locked_in_modes_2_and_3() {
+    let mut mem = create_memmap(0);
+    mem.set_byte(0xfe04, 0x20);
+    mem.zero_page[0x40] = 0x80;
+    for mode in 2..4 {
+      mem.zero_page[0x41] = mode;
+      assert_eq!(mem.get_byte(0xfe04), 0xff);
+      mem.set_byte(0xfe04, 0x40);
+    }
+    mem.zero_page[0x41] = 0x1;
+    assert_eq!(mem.get_byte(0xfe04), 0x20);
+  }
+
+  #[test]
+  fn vram_unlocked_when_lcd_off_or_unrestricted() {
+    let mut mem = create_memmap(0);
+    mem.zero_page[0x41] = 0x3;
+    mem.set_byte(0x9800, 0x1);
+    mem.set_byte(0xfe00, 0x2);
+    assert_eq!(mem.get_byte(0x9800), 0x1);
+    assert_eq!(mem.get_byte(0xfe00), 0x2);
+    mem.zero_page[0x40] = 0x80;
+    mem.restrict_vram_access = false;
+    mem.set_byte(0x9800, 0x3);
+    assert_eq!(mem.get_byte(0x9800), 0x3);
+    assert_eq!(mem.get_byte(0xfe00), 0x2);
+  }
+
+  #[test]
+  fn sgb_multiplayer_pads() {
+    let mut mem = create_memmap(0);
+    mem.sgb.enabled = true;
+    mem.sgb.player_count = 4;
+    mem.set_player_buttons(0, 0x5);
+    mem.set_player_buttons(2, 0x7);
+    // Rotates to player 1, reading back its ID rather than player 0's pad
+    mem.set_byte(0xff00, 0x30);
+    assert_eq!(mem.get_byte(0xff00) & 0xf, 0xe);
+    mem.set_byte(0xff00, 0x10);
+    assert_eq!(mem.get_byte(0xff00) & 0xf, 0xf);
+    mem.set_byte(0xff00, 0x30);
+    assert_eq!(mem.get_byte(0xff00) & 0xf, 0xd);
+    // Staying high does not rotate again
+    mem.set_byte(0xff00, 0x30);
+    assert_eq!(mem.get_byte(0xff00) & 0xf, 0xd);
+    mem.set_byte(0xff00, 0x10);
+    assert_eq!(mem.get_byte(0xff00) & 0xf, 0x7);
+    mem.set_byte(0xff00, 0x30);
+    mem.set_byte(0xff00, 0x20);
+    mem.set_byte(0xff00, 0x30);
+    assert_eq!(mem.get_byte(0xff00) & 0xf, 0xf);
+    mem.sgb.player_count = 1;
+    mem.set_byte(0xff00, 0x10);
+    assert_eq!(mem.get_byte(0xff00) & 0xf, 0x5);
+  }
+
+  #[test]
+  fn serial_transfer_raises_interrupt() {
+    let mut mem = create_memmap(0);
+    mem.set_byte(0xff01, 0x55);
+    mem.set_byte(0xff02, 0x81);
+    for _ in 0..(4096 / 16 - 1) {
+      mem.add_time(16);
+    }
+    assert_eq!(mem.get_byte(0xff0f) & 8, 0);
+    assert_eq!(mem.get_byte(0xff02), 0xff);
+    mem.add_time(16);
+    assert_eq!(mem.get_byte(0xff0f) & 8, 8);
+    assert_eq!(mem.get_byte(0xff01), 0xff);
+    assert_eq!(mem.get_byte(0xff02), 0x7f);
+  }
+
+  #[test]
+  fn cgb_boot_rom_mapping() {
+    let mut mem = create_memmap(0);
+    mem.boot[0x00] = 0x31;
+    mem.boot[0x150] = 0x99;
+    mem.boot[0x200] = 0x42;
+    mem.cart.raw_rom[0x150] = 0x11;
+    mem.cart.raw_rom[0x200] = 0x22;
+    mem.map_boot_rom(0x900);
+    assert!(mem.cgb_mode);
+    assert_eq!(mem.get_byte(0x0000), 0x31);
+    // The cart header shows through between the two halves
+    assert_eq!(mem.get_byte(0x0150), 0x11);
+    assert_eq!(mem.get_byte(0x0200), 0x42);
+    mem.set_byte(0xff4c, 0x04);
+    assert!(!mem.cgb_mode);
+    mem.set_byte(0xff50, 0x11);
+    assert_eq!(mem.get_byte(0x0000), 0x00);
+    assert_eq!(mem.get_byte(0x0200), 0x22);
+
+    mem.map_boot_rom(0x100);
+    assert!(!mem.cgb_mode);
+    assert_eq!(mem.get_byte(0x0000), 0x31);
+    assert_eq!(mem.get_byte(0x0200), 0x22);
+  }
+
+  #[test]
+  fn seeded_power_on_ram() {
+    let mut a = create_memmap(0);
+    let mut b = create_memmap(0);
+    a.initialize_ram(&mut create_rng(99), RamInit::Random);
+    b.initialize_ram(&mut create_rng(99), RamInit::Random);
+    assert_eq!(&a.work_ram[..], &b.work_ram[..]);
+    assert_eq!(&a.video_ram[..], &b.video_ram[..]);
+    assert!(a.work_ram.iter().any(|v| *v != 0));
+    assert!(a.sprite_table.iter().any(|v| *v != 0));
+    assert_eq!(a.get_byte(0xff80), b.get_byte(0xff80));
+    assert_eq!(a.get_byte(0xffff), 0);
+  }
 }
@@ -1,9 +1,22 @@
 pub mod audio;
 pub mod cart;
 pub mod cpu;
+pub mod gbs;
 pub mod gpu;
+pub mod link;
 pub mod memmap;
+pub mod model;
+pub mod netlink;
+pub mod palette;
+pub mod png;
+pub mod printer;
+pub mod random;
+pub mod serial;
+pub mod sgb;
+pub mod vgm;
+pub mod wav;
 
+#[cfg(not(test))]
 extern "C" {
   fn copy_tile_data();
   fn copy_map_0_data();
@@ -11,15 +24,43 @@ extern "C" {
   fn draw_gl();
 }
 
+#[cfg(test)]
+unsafe fn copy_tile_data() {}
+#[cfg(test)]
+unsafe fn copy_map_0_data() {}
+#[cfg(test)]
+unsafe fn copy_map_1_data() {}
+#[cfg(test)]
+unsafe fn draw_gl() {}
+
 pub struct VM {
   pub cpu: cpu::CPU,
   pub gpu: gpu::GPU,
   pub mem: memmap::MemMap,
   pub state: cpu::RunState,
+  // Hardware to emulate, picked from the cart header when not set
+  pub model: Option<model::Model>,
+  // Set when playing a GBS file instead of running a cart
+  pub gbs: Option<gbs::GbsPlayer>,
+  // Seeded by power_on, garbles the registers on the next boot ROM reset
+  power_on_rng: Option<random::Rng>,
 
   pub breakpoints: Vec<u16>,
 }
 
+pub fn create_vm() -> VM {
+  return VM {
+    cpu: cpu::create_cpu(),
+    gpu: gpu::create_gpu(),
+    mem: memmap::create_memmap(0),
+    state: cpu::RunState::Run,
+    model: None,
+    gbs: None,
+    power_on_rng: None,
+    breakpoints: vec![0x100],
+  };
+}
+
 impl VM {
 pub fn step(&mut self) {
   let (state, _) = self.cpu.step(&mut self.mem);
@@ -27,102 +68,266 @@ pub fn step(&mut self) {
 }
 
 pub fn frame(&mut self) -> bool {
-  let mut cpu_state = self.state;
-  let mut cycles = 4;
-  let mut gpu_action = gpu::GPUAction::Noop;
   let mut breakpoint = false;
-  while gpu_action != gpu::GPUAction::FlushBuffer {
-    if !breakpoint && cpu_state == cpu::RunState::Run {
-      let (s, c) = self.cpu.step(&mut self.mem);
-      cpu_state = s;
-      cycles = c;
+  loop {
+    let (_, action, hit) = self.tick(breakpoint);
+    breakpoint = breakpoint || hit;
+    if action == gpu::GPUAction::FlushBuffer {
+      break;
     }
+  }
+  return breakpoint;
+}
 
-    if self.breakpoints.contains(&self.cpu.get_register_16(cpu::Register16::PC)) {
-      breakpoint = true;
-    }
+// Runs a single CPU instruction, or a few idle cycles while halted or paused,
+// and advances the rest of the hardware by the same amount. Returns the
+// cycles taken, the GPU action, and whether a breakpoint was reached.
+pub fn tick(&mut self, paused: bool) -> (u8, gpu::GPUAction, bool) {
+  let mut cycles = 4;
+  let mut breakpoint = false;
+  let stall = self.mem.consume_cpu_stall();
+  if stall > 0 {
+    // VRAM DMA is holding the bus
+    cycles = stall;
+  } else if !paused && self.state == cpu::RunState::Run {
+    let (s, c) = self.cpu.step(&mut self.mem);
+    self.state = s;
+    cycles = c;
+  }
+
+  if self.breakpoints.contains(&self.cpu.get_register_16(cpu::Register16::PC)) {
+    breakpoint = true;
+  }
+
+  let time = cycles;
+  // The PPU runs in real time, so it only sees half the cycles in double speed
+  let gpu_time = if self.mem.is_double_speed() { time / 2 } else { time };
+
+  let gpu_action = self.gpu.add_clock_time(&mut self.mem, gpu_time);
+  self.mem.add_time(time);
 
-    self.state = cpu_state;
-
-    let time = cycles;
-
-    gpu_action = self.gpu.add_clock_time(&mut self.mem, time);
-    self.mem.add_time(time);
-
-    match gpu_action {
-      gpu::GPUAction::RenderScanline(_line) => {
-        // Handled in WebGL now
-      },
-
-      gpu::GPUAction::IncrementLine(line) => {
-        self.mem.set_byte(0xff44, line);
-      },
-
-      gpu::GPUAction::FlushBuffer => {
-        self.mem.set_byte(0xff44, 143);
-        unsafe {
-          if self.mem.is_tile_data_dirty() {
-            copy_tile_data();
-          }
-          if self.mem.is_tile_map_0_dirty() {
-            copy_map_0_data();
-          }
-          if self.mem.is_tile_map_1_dirty() {
-            copy_map_1_data();
-          }
-          draw_gl();
+  match gpu_action {
+    gpu::GPUAction::RenderScanline(_line) => {
+      // Handled in WebGL now
+    },
+
+    gpu::GPUAction::IncrementLine(line) => {
+      self.mem.set_byte(0xff44, line);
+    },
+
+    gpu::GPUAction::FlushBuffer => {
+      self.mem.set_byte(0xff44, 144);
+      unsafe {
+        if self.mem.is_tile_data_dirty() {
+          copy_tile_data();
+        }
+        if self.mem.is_tile_map_0_dirty() {
+          copy_map_0_data();
+        }
+        if self.mem.is_tile_map_1_dirty() {
+          copy_map_1_data();
         }
-      },
+        draw_gl();
+      }
+    },
+
+    _ => {},
+  }
+
+  if !paused && !breakpoint {
+    self.handle_interrupts();
+  }
+  return (cycles, gpu_action, breakpoint);
+}
 
-      _ => {},
+fn handle_interrupts(&mut self) {
+  if !self.cpu.interrupt_enabled() {
+    return;
+  }
+  let i_enabled = self.mem.get_byte(0xffff);
+  let i_fired = self.mem.get_byte(0xff0f);
+  let mut i_fired_reset = i_fired;
+  let fired = i_enabled & i_fired;
+  if fired > 0 {
+    if self.state != cpu::RunState::Run {
+      self.state = cpu::RunState::Run;
+    }
+    // Perform triggered interrupts
+    if fired & 1 > 0 {
+      // VBlank
+      i_fired_reset = i_fired_reset & 0xfe;
+      self.mem.set_byte(0xff0f, i_fired_reset);
+      self.cpu.int_vblank(&mut self.mem);
+    }
+    if fired & 2 > 0 {
+      // LCD STAT
+      i_fired_reset = i_fired_reset & 0xfd;
+      self.mem.set_byte(0xff0f, i_fired_reset);
+      self.cpu.int_stat(&mut self.mem);
+    }
+    if fired & 4 > 0 {
+      // Timer
+      i_fired_reset = i_fired_reset & 0xfb;
+      self.mem.set_byte(0xff0f, i_fired_reset);
+      self.cpu.int_timer(&mut self.mem);
+    }
+    if fired & 8 > 0 {
+      // Serial
+      i_fired_reset = i_fired_reset & 0xf7;
+      self.mem.set_byte(0xff0f, i_fired_reset);
+      self.cpu.int_serial(&mut self.mem);
+    }
+    if fired & 16 > 0 {
+      // Joypad
+      i_fired_reset = i_fired_reset & 0xef;
+      self.mem.set_byte(0xff0f, i_fired_reset);
+      self.cpu.int_joypad(&mut self.mem);
     }
+  }
+}
 
-    if !breakpoint {
-      if self.cpu.interrupt_enabled() {
-        let i_enabled = self.mem.get_byte(0xffff);
-        let i_fired = self.mem.get_byte(0xff0f);
-        let mut i_fired_reset = i_fired;
-        let fired = i_enabled & i_fired;
-        if fired > 0 {
-          if self.state != cpu::RunState::Run {
-            self.state = cpu::RunState::Run;
-          }
-          // Perform triggered interrupts
-          if fired & 1 > 0 {
-            // VBlank
-            i_fired_reset = i_fired_reset & 0xfe;
-            self.mem.set_byte(0xff0f, i_fired_reset);
-            self.cpu.int_vblank(&mut self.mem);
-          }
-          if fired & 2 > 0 {
-            // LCD STAT
-            i_fired_reset = i_fired_reset & 0xfd;
-            self.mem.set_byte(0xff0f, i_fired_reset);
-            self.cpu.int_stat(&mut self.mem);
-          }
-          if fired & 4 > 0 {
-            // Timer
-            i_fired_reset = i_fired_reset & 0xfb;
-            self.mem.set_byte(0xff0f, i_fired_reset);
-            self.cpu.int_timer(&mut self.mem);
-          }
-          if fired & 8 > 0 {
-            // Serial
-          }
-          if fired & 16 > 0 {
-            // Joypad
-            i_fired_reset = i_fired_reset & 0xef;
-            self.mem.set_byte(0xff0f, i_fired_reset);
-            self.cpu.int_joypad(&mut self.mem);
-          }
-        }
+// Starts from the boot ROM copied to the boot pointer. A 2304 byte image is
+// the CGB boot ROM, which also maps 0x200-0x8ff around the cart header.
+pub fn reset_with_boot_rom(&mut self, len: usize) {
+  self.gbs = None;
+  self.cpu.reset();
+  if let Some(mut rng) = self.power_on_rng.take() {
+    self.cpu.randomize_registers(&mut rng);
+  }
+  self.state = cpu::RunState::Run;
+  self.mem.map_boot_rom(len);
+}
+
+// Fills RAM the way it might be found at power on, so code relying on zeroed
+// memory shows up. The same seed gives the same state. Registers are garbled
+// by the next reset_with_boot_rom; skipping the boot ROM always leaves the
+// state it would have.
+pub fn power_on(&mut self, init: random::RamInit, seed: u32) {
+  let mut rng = random::create_rng(seed);
+  self.mem.initialize_ram(&mut rng, init);
+  self.power_on_rng = if init == random::RamInit::Zero { None } else { Some(rng) };
+}
+
+// Skips the boot ROM, starting at 0x100 with the state it leaves behind
+pub fn reset_after_bootloader(&mut self, model: model::Model) {
+  self.cpu.reset();
+  self.state = cpu::RunState::Run;
+  self.cpu.simulate_bootloader(model);
+  self.mem.simulate_bootloader(model);
+}
+
+// Switches to playing a GBS file, starting with its first song
+pub fn load_gbs(&mut self, data: &[u8]) -> Result<(), gbs::GbsError> {
+  let header = gbs::parse_header(data)?;
+  gbs::load_rom(&mut self.mem.cart, &header, &data[gbs::HEADER_SIZE..]);
+  let player = gbs::create_player(header);
+  let track = player.track;
+  self.gbs = Some(player);
+  self.start_gbs_track(track);
+  return Ok(());
+}
+
+// Resets the hardware and calls INIT for a 0-based track number
+pub fn start_gbs_track(&mut self, track: u8) {
+  let (init_addr, stack_pointer, timer_modulo, timer_control) = match self.gbs {
+    Some(ref mut player) => {
+      if track >= player.header.song_count {
+        return;
       }
+      player.start_track(track);
+      let header = &player.header;
+      (header.init_addr, header.stack_pointer, header.timer_modulo, header.timer_control)
+    },
+    None => return,
+  };
+  let double_speed = timer_control & 0x80 > 0;
+  let model = if double_speed { model::Model::Cgb } else { model::Model::Dmg };
+  self.mem.initialize_ram(&mut random::create_rng(0), random::RamInit::Zero);
+  self.mem.set_byte(0xff26, 0x00);
+  self.reset_after_bootloader(model);
+  self.mem.set_cgb_mode(double_speed);
+  if double_speed {
+    self.mem.switch_speed();
+  }
+  self.mem.cart.set_rom_bank(1);
+  self.mem.set_byte(0xff06, timer_modulo);
+  self.mem.set_byte(0xff07, timer_control & 0x7);
+  self.cpu.set_register_16(cpu::Register16::SP, stack_pointer);
+  self.cpu.set_register_8(cpu::Register8::A, track);
+  self.call_gbs_routine(init_addr);
+}
+
+fn call_gbs_routine(&mut self, addr: u16) {
+  self.cpu.push(&mut self.mem, gbs::IDLE_ADDR);
+  self.cpu.set_register_16(cpu::Register16::PC, addr);
+  self.state = cpu::RunState::Run;
+}
+
+// Runs a frame's worth of GBS playback without the PPU. Returns true once the
+// track has played for its duration.
+pub fn gbs_frame(&mut self) -> bool {
+  let double_speed = self.mem.is_double_speed();
+  let frame_cycles = if double_speed { 140448 } else { 70224 };
+  let mut elapsed = 0;
+  while elapsed < frame_cycles {
+    let mut cycles = 4;
+    if self.state == cpu::RunState::Run {
+      let (s, c) = self.cpu.step(&mut self.mem);
+      self.state = s;
+      cycles = c;
+    }
+    self.mem.add_time(cycles);
+    self.handle_interrupts();
+    elapsed += cycles as u32;
+
+    let play = match self.gbs {
+      Some(ref mut player) => player.add_time(cycles as u32, double_speed),
+      None => return true,
+    };
+    let pc = self.cpu.get_register_16(cpu::Register16::PC);
+    let idle = pc >= gbs::IDLE_ADDR && pc < gbs::IDLE_ADDR + 3;
+    if play && idle {
+      // A PLAY that runs long skips the next call, like on hardware players
+      let play_addr = self.gbs.as_ref().unwrap().header.play_addr;
+      self.call_gbs_routine(play_addr);
     }
   }
-  return breakpoint;
+  return self.gbs.as_ref().unwrap().is_finished();
 }
 
 pub fn set_mbc(&mut self, mbc: u8) {
   self.mem.cart.set_mbc(mbc);
 }
-}
\ No newline at end of file
+
+// 0x80 marks a CGB-enhanced cart, 0xc0 a CGB-only cart
+fn is_cgb_cart(&self) -> bool {
+  let cgb_flag = self.mem.cart.get_rom_byte(0x143);
+  return cgb_flag == 0x80 || cgb_flag == 0xc0;
+}
+
+// The model picked by the host, or the one the cart header asks for
+pub fn cart_model(&self) -> model::Model {
+  return match self.model {
+    Some(m) => m,
+    None => if self.is_cgb_cart() { model::Model::Cgb } else { model::Model::Dmg },
+  };
+}
+
+// Turns on the model's features the cart can use. Call after the reset, which
+// should already be for cart_model() when skipping the boot ROM. A running
+// boot ROM picks CGB or DMG mode itself through KEY0.
+pub fn apply_cart_header(&mut self) {
+  // A cart has been loaded, so any GBS file is gone
+  self.gbs = None;
+  let model = self.cart_model();
+  if self.model.is_some() {
+    if let Some(preset) = model::palette_preset(model) {
+      self.gpu.apply_palette_preset(preset);
+    }
+  }
+  if !self.mem.is_boot_rom_running() {
+    self.mem.set_cgb_mode(model::has_cgb(model) && self.is_cgb_cart());
+  }
+  self.mem.sgb.enabled = model::has_sgb(model);
+}
+}
@@ -0,0 +1,175 @@
+use vm::palette::Preset;
+
+// Hardware revisions, which games tell apart by the registers the boot ROM
+// leaves behind
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Model {
+  Dmg,
+  Mgb,
+  Sgb,
+  Cgb,
+  Dmg0,
+  Sgb2,
+  Agb,
+}
+
+pub struct Registers {
+  pub a: u8,
+  pub flags: u8,
+  pub b: u8,
+  pub c: u8,
+  pub d: u8,
+  pub e: u8,
+  pub h: u8,
+  pub l: u8,
+}
+
+pub fn model_from_index(index: u8) -> Option<Model> {
+  return match index {
+    0 => Some(Model::Dmg),
+    1 => Some(Model::Mgb),
+    2 => Some(Model::Sgb),
+    3 => Some(Model::Cgb),
+    4 => Some(Model::Dmg0),
+    5 => Some(Model::Sgb2),
+    6 => Some(Model::Agb),
+    _ => None,
+  };
+}
+
+// CPU registers when each model's boot ROM hands over to the cart at 0x100
+pub fn post_boot_registers(model: Model) -> Registers {
+  return match model {
+    Model::Dmg0 => Registers { a: 0x01, flags: 0x00, b: 0xff, c: 0x13, d: 0x00, e: 0xc1, h: 0x84, l: 0x03 },
+    Model::Dmg => Registers { a: 0x01, flags: 0xb0, b: 0x00, c: 0x13, d: 0x00, e: 0xd8, h: 0x01, l: 0x4d },
+    Model::Mgb => Registers { a: 0xff, flags: 0xb0, b: 0x00, c: 0x13, d: 0x00, e: 0xd8, h: 0x01, l: 0x4d },
+    Model::Sgb => Registers { a: 0x01, flags: 0x00, b: 0x00, c: 0x14, d: 0x00, e: 0x00, h: 0xc0, l: 0x60 },
+    Model::Sgb2 => Registers { a: 0xff, flags: 0x00, b: 0x00, c: 0x14, d: 0x00, e: 0x00, h: 0xc0, l: 0x60 },
+    Model::Cgb => Registers { a: 0x11, flags: 0x80, b: 0x00, c: 0x00, d: 0xff, e: 0x56, h: 0x00, l: 0x0d },
+    Model::Agb => Registers { a: 0x11, flags: 0x00, b: 0x01, c: 0x00, d: 0xff, e: 0x56, h: 0x00, l: 0x0d },
+  };
+}
+
+// I/O registers that differ between models when the boot ROM hands over.
+// SC and the CGB registers read back by mode and STAT follows the PPU, so
+// they aren't listed.
+pub struct IoRegisters {
+  // Depends on how long each boot ROM runs
+  pub div: u8,
+  // Only the SGB boot ROM doesn't play the startup sound on channel 1
+  pub nr52: u8,
+  pub dma: u8,
+}
+
+pub fn post_boot_io(model: Model) -> IoRegisters {
+  return match model {
+    Model::Dmg0 => IoRegisters { div: 0x18, nr52: 0xf1, dma: 0xff },
+    Model::Dmg | Model::Mgb => IoRegisters { div: 0xab, nr52: 0xf1, dma: 0xff },
+    Model::Sgb | Model::Sgb2 => IoRegisters { div: 0x00, nr52: 0xf0, dma: 0xff },
+    Model::Cgb | Model::Agb => IoRegisters { div: 0x00, nr52: 0xf1, dma: 0x00 },
+  };
+}
+
+pub fn has_cgb(model: Model) -> bool {
+  return model == Model::Cgb || model == Model::Agb;
+}
+
+pub fn has_sgb(model: Model) -> bool {
+  return model == Model::Sgb || model == Model::Sgb2;
+}
+
+// Shades used for DMG games. CGB models have none, their colors come from
+// the CGB palettes.
+pub fn palette_preset(model: Model) -> Option<Preset> {
+  return match model {
+    Model::Dmg0 | Model::Dmg => Some(Preset::OriginalGreen),
+    Model::Mgb => Some(Preset::PocketGray),
+    Model::Sgb | Model::Sgb2 => Some(Preset::SuperGameBoy),
+    Model::Cgb | Model::Agb => None,
+  };
+}
+
+#[cfg(test)]
+mod tests {
+  use vm::cpu::Register8;
+  use vm::create_vm;
+  use vm::model::Model;
+
+  #[test]
+  fn cart_header_picks_model_features() {
+    let mut vm = create_vm();
+    vm.mem.cart.raw_rom[0x143] = 0x80;
+    assert_eq!(vm.cart_model(), Model::Cgb);
+    let model = vm.cart_model();
+    vm.reset_after_bootloader(model);
+    vm.apply_cart_header();
+    assert!(vm.mem.cgb_mode);
+    assert_eq!(vm.cpu.get_register_8(Register8::A), 0x11);
+
+    vm.model = Some(Model::Mgb);
+    vm.reset_after_bootloader(Model::Mgb);
+    vm.apply_cart_header();
+    assert!(!vm.mem.cgb_mode);
+    assert!(!vm.mem.sgb.enabled);
+    assert_eq!(vm.cpu.get_register_8(Register8::A), 0xff);
+    assert_eq!(vm.gpu.palettes[0][3], 0x1f1f1f);
+    assert_eq!(vm.mem.get_byte(0xff04), 0xab);
+    assert_eq!(vm.mem.get_byte(0xff26), 0xf1);
+    assert_eq!(vm.mem.get_byte(0xff46), 0xff);
+
+    vm.model = Some(Model::Sgb2);
+    vm.reset_after_bootloader(Model::Sgb2);
+    vm.apply_cart_header();
+    assert!(vm.mem.sgb.enabled);
+    assert_eq!(vm.cpu.get_register_8(Register8::C), 0x14);
+    // No startup sound
+    assert_eq!(vm.mem.get_byte(0xff26), 0xf0);
+    assert_eq!(vm.gpu.palettes[0][0], 0xf7e7c6);
+
+    // CGB models leave the DMG shades alone
+    vm.model = Some(Model::Agb);
+    vm.reset_after_bootloader(Model::Agb);
+    vm.apply_cart_header();
+    assert!(vm.mem.cgb_mode);
+    assert_eq!(vm.cpu.get_register_8(Register8::B), 0x01);
+    assert_eq!(vm.mem.get_byte(0xff46), 0x00);
+    assert_eq!(vm.gpu.palettes[0][0], 0xf7e7c6);
+  }
+
+  #[test]
+  fn cgb_boot_rom_picks_mode() {
+    let mut vm = create_vm();
+    vm.model = Some(Model::Cgb);
+    // The order hosts use: boot ROM reset, then the cart header
+    vm.reset_with_boot_rom(0x900);
+    vm.mem.set_byte(0xff4f, 0x01);
+    vm.apply_cart_header();
+    assert!(vm.mem.cgb_mode);
+    assert_eq!(vm.mem.get_byte(0xff4f), 0xff);
+    // Left to the boot ROM, which drops a DMG cart to DMG mode
+    vm.mem.set_byte(0xff4c, 0x04);
+    vm.mem.set_byte(0xff50, 0x11);
+    assert!(!vm.mem.cgb_mode);
+
+    vm.model = None;
+    vm.mem.cart.raw_rom[0x143] = 0x80;
+    vm.reset_with_boot_rom(0x900);
+    vm.apply_cart_header();
+    vm.mem.set_byte(0xff4c, 0x80);
+    vm.mem.set_byte(0xff50, 0x11);
+    assert!(vm.mem.cgb_mode);
+  }
+
+  #[test]
+  fn cart_header_keeps_host_state() {
+    let mut vm = create_vm();
+    vm.reset_after_bootloader(Model::Dmg);
+    // Set by the host after the reset, at 0x100 with the boot ROM unmapped
+    vm.cpu.set_register_8(Register8::A, 0x42);
+    vm.mem.set_byte(0xc000, 0x99);
+    vm.apply_cart_header();
+    assert_eq!(vm.cpu.get_register_8(Register8::A), 0x42);
+    assert_eq!(vm.mem.get_byte(0xc000), 0x99);
+  }
+}
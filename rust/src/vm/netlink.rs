@@ -0,0 +1,550 @@
+use std::any::Any;
+use std::fmt;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::net::ToSocketAddrs;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::Path;
+use vm::serial::SerialDevice;
+
+const MAGIC: &[u8; 4] = b"GBLK";
+pub const PROTOCOL_VERSION: u8 = 2;
+
+// Messages are a kind byte, a sequence number and the serial byte. The
+// sequence number counts the transfers clocked by the master so far.
+const MESSAGE_SIZE: usize = 3;
+// The externally clocked side publishes SB as soon as it arms a transfer
+const MSG_READY: u8 = 0x01;
+// The internally clocked side sends its byte when it starts a transfer
+const MSG_TRANSFER: u8 = 0x02;
+
+// How long connecting, accepting and the handshake may take. These run on
+// the host's UI thread, so they can't be left to block forever.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug)]
+pub enum LinkError {
+  Io(io::Error),
+  BadMagic,
+  VersionMismatch(u8),
+}
+
+impl fmt::Display for LinkError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    return match *self {
+      LinkError::Io(ref err) => write!(f, "link socket error: {}", err),
+      LinkError::BadMagic => write!(f, "peer is not a link cable"),
+      LinkError::VersionMismatch(version) => {
+        write!(f, "peer speaks link protocol {}, expected {}", version, PROTOCOL_VERSION)
+      },
+    };
+  }
+}
+
+impl From<io::Error> for LinkError {
+  fn from(err: io::Error) -> LinkError {
+    return LinkError::Io(err);
+  }
+}
+
+// Streams that time out during the handshake and are switched to
+// non-blocking once it's done
+pub trait LinkStream: Read + Write {
+  fn set_nonblocking(&self, flag: bool) -> io::Result<()>;
+  fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl LinkStream for TcpStream {
+  fn set_nonblocking(&self, flag: bool) -> io::Result<()> {
+    return TcpStream::set_nonblocking(self, flag);
+  }
+
+  fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+    self.set_read_timeout(timeout)?;
+    return self.set_write_timeout(timeout);
+  }
+}
+
+#[cfg(unix)]
+impl LinkStream for UnixStream {
+  fn set_nonblocking(&self, flag: bool) -> io::Result<()> {
+    return UnixStream::set_nonblocking(self, flag);
+  }
+
+  fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+    self.set_read_timeout(timeout)?;
+    return self.set_write_timeout(timeout);
+  }
+}
+
+// Polls a non-blocking listener until a peer connects or the timeout passes
+fn accept_within<T, F: FnMut() -> io::Result<T>>(mut accept: F) -> Result<T, LinkError> {
+  let start = Instant::now();
+  loop {
+    match accept() {
+      Ok(value) => return Ok(value),
+      Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+        if start.elapsed() >= CONNECT_TIMEOUT {
+          return Err(LinkError::Io(io::Error::new(io::ErrorKind::TimedOut, "no peer connected")));
+        }
+        thread::sleep(Duration::from_millis(10));
+      },
+      Err(e) => return Err(LinkError::Io(e)),
+    }
+  }
+}
+
+// A link cable to another emulator over a socket. Waiting on the network for
+// every bit would stall both machines, so the socket is never blocked on:
+// the slave publishes its byte when it arms a transfer, and the master holds
+// its transfer for as long as it takes the publication for that transfer to
+// arrive. The slave completes once the master's byte arrives. Sequence
+// numbers keep a slow publication from being mistaken for the next one.
+pub struct SocketLink<S: LinkStream> {
+  stream: S,
+  connected: bool,
+  received: Vec<u8>,
+  // Messages the socket hasn't taken yet, sent before anything else is read
+  outgoing: Vec<u8>,
+
+  // Transfers we clocked as the master, and were clocked as the slave
+  sent: u8,
+  clocked: u8,
+
+  // Latest sequence number and byte the other side published while waiting
+  // for a clock
+  remote: Option<(u8, u8)>,
+  // Byte we published while waiting, so it's only sent once
+  published: Option<u8>,
+  // Bytes clocked in by the other side, oldest first
+  transfers: Vec<u8>,
+}
+
+pub fn create_socket_link<S: LinkStream>(mut stream: S) -> Result<SocketLink<S>, LinkError> {
+  stream.set_nonblocking(false)?;
+  stream.set_timeout(Some(CONNECT_TIMEOUT))?;
+  let mut hello = [0; 5];
+  hello[0..4].copy_from_slice(MAGIC);
+  hello[4] = PROTOCOL_VERSION;
+  stream.write_all(&hello)?;
+  let mut peer = [0; 5];
+  stream.read_exact(&mut peer)?;
+  if &peer[0..4] != MAGIC {
+    return Err(LinkError::BadMagic);
+  }
+  if peer[4] != PROTOCOL_VERSION {
+    return Err(LinkError::VersionMismatch(peer[4]));
+  }
+  stream.set_nonblocking(true)?;
+  return Ok(SocketLink {
+    stream: stream,
+    connected: true,
+    received: Vec::new(),
+    outgoing: Vec::new(),
+
+    sent: 0,
+    clocked: 0,
+
+    remote: None,
+    published: None,
+    transfers: Vec::new(),
+  });
+}
+
+pub fn connect_tcp<A: ToSocketAddrs>(addr: A) -> Result<SocketLink<TcpStream>, LinkError> {
+  let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to");
+  for addr in addr.to_socket_addrs()? {
+    match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+      Ok(stream) => {
+        stream.set_nodelay(true)?;
+        return create_socket_link(stream);
+      },
+      Err(e) => last_error = e,
+    }
+  }
+  return Err(LinkError::Io(last_error));
+}
+
+// Switches the listener to non-blocking so giving up on a peer is possible
+pub fn accept_tcp(listener: &TcpListener) -> Result<SocketLink<TcpStream>, LinkError> {
+  listener.set_nonblocking(true)?;
+  let (stream, _) = accept_within(|| listener.accept())?;
+  stream.set_nodelay(true)?;
+  return create_socket_link(stream);
+}
+
+#[cfg(unix)]
+pub fn connect_unix<P: AsRef<Path>>(path: P) -> Result<SocketLink<UnixStream>, LinkError> {
+  return create_socket_link(UnixStream::connect(path)?);
+}
+
+#[cfg(unix)]
+pub fn accept_unix(listener: &UnixListener) -> Result<SocketLink<UnixStream>, LinkError> {
+  listener.set_nonblocking(true)?;
+  let (stream, _) = accept_within(|| listener.accept())?;
+  return create_socket_link(stream);
+}
+
+// Opens a link to another emulator, either dialing `addr` or listening on it
+// for a single connection. Unix sockets take a path instead of host:port.
+pub fn open_link(addr: &str, listen: bool, unix: bool) -> Result<Box<dyn SerialDevice>, LinkError> {
+  if unix {
+    return open_unix_link(addr, listen);
+  }
+  if listen {
+    let listener = TcpListener::bind(addr)?;
+    return Ok(Box::new(accept_tcp(&listener)?));
+  }
+  return Ok(Box::new(connect_tcp(addr)?));
+}
+
+#[cfg(unix)]
+fn open_unix_link(path: &str, listen: bool) -> Result<Box<dyn SerialDevice>, LinkError> {
+  if listen {
+    let listener = UnixListener::bind(path)?;
+    return Ok(Box::new(accept_unix(&listener)?));
+  }
+  return Ok(Box::new(connect_unix(path)?));
+}
+
+#[cfg(not(unix))]
+fn open_unix_link(_path: &str, _listen: bool) -> Result<Box<dyn SerialDevice>, LinkError> {
+  return Err(LinkError::Io(io::Error::new(io::ErrorKind::Other, "unix sockets are not supported")));
+}
+
+impl<S: LinkStream> SocketLink<S> {
+  fn send(&mut self, kind: u8, sequence: u8, byte: u8) {
+    if !self.connected {
+      return;
+    }
+    self.outgoing.extend_from_slice(&[kind, sequence, byte]);
+    self.flush();
+  }
+
+  // Writes as much of the queue as the socket takes without blocking
+  fn flush(&mut self) {
+    while self.connected && !self.outgoing.is_empty() {
+      match self.stream.write(&self.outgoing) {
+        Ok(0) => self.connected = false,
+        Ok(n) => {
+          self.outgoing.drain(0..n);
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+        Err(_) => self.connected = false,
+      }
+    }
+  }
+
+  // The byte the other side published for the transfer we're about to clock
+  fn ready_byte(&self) -> Option<u8> {
+    return match self.remote {
+      Some((sequence, byte)) if sequence == self.sent => Some(byte),
+      _ => None,
+    };
+  }
+
+  // Reads whatever has arrived without waiting for more
+  fn receive(&mut self) {
+    self.flush();
+    let mut buffer = [0; 64];
+    while self.connected {
+      match self.stream.read(&mut buffer) {
+        Ok(0) => self.connected = false,
+        Ok(n) => self.received.extend_from_slice(&buffer[0..n]),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+        Err(_) => self.connected = false,
+      }
+    }
+    let whole = self.received.len() - self.received.len() % MESSAGE_SIZE;
+    let messages: Vec<u8> = self.received.drain(0..whole).collect();
+    for message in messages.chunks(MESSAGE_SIZE) {
+      match message[0] {
+        MSG_READY => self.remote = Some((message[1], message[2])),
+        MSG_TRANSFER => self.transfers.push(message[2]),
+        _ => (),
+      }
+    }
+  }
+}
+
+impl<S: LinkStream + 'static> SerialDevice for SocketLink<S> {
+  // Giving up on a connected peer would leave the two sides disagreeing on
+  // the transfer, so only a disconnect ends the wait
+  fn can_exchange(&mut self) -> bool {
+    self.receive();
+    return !self.connected || self.ready_byte().is_some();
+  }
+
+  fn exchange(&mut self, byte: u8) -> u8 {
+    self.receive();
+    if !self.connected {
+      return 0xff;
+    }
+    let value = self.ready_byte().unwrap_or(0xff);
+    let sequence = self.sent;
+    self.send(MSG_TRANSFER, sequence, byte);
+    self.sent = self.sent.wrapping_add(1);
+    return value;
+  }
+
+  fn poll(&mut self, byte: u8) -> Option<u8> {
+    if self.published != Some(byte) {
+      let sequence = self.clocked;
+      self.send(MSG_READY, sequence, byte);
+      self.published = Some(byte);
+    }
+    self.receive();
+    if self.transfers.is_empty() {
+      return None;
+    }
+    self.published = None;
+    self.clocked = self.clocked.wrapping_add(1);
+    return Some(self.transfers.remove(0));
+  }
+
+  fn as_any(&mut self) -> &mut dyn Any {
+    return self;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::cell::RefCell;
+  use std::io;
+  use std::io::Read;
+  use std::io::Write;
+  use std::net::TcpListener;
+  use std::net::TcpStream;
+  use std::rc::Rc;
+  use std::sync::mpsc;
+  use std::thread;
+  use std::time::Duration;
+  use vm::netlink::accept_tcp;
+  use vm::netlink::connect_tcp;
+  use vm::netlink::create_socket_link;
+  use vm::netlink::LinkError;
+  use vm::netlink::LinkStream;
+  use vm::netlink::MSG_READY;
+  use vm::serial::SerialDevice;
+
+  // A socket whose peer has said hello, and that only takes `room` bytes
+  // before it would block
+  struct SlowStream {
+    incoming: Vec<u8>,
+    written: Rc<RefCell<Vec<u8>>>,
+    room: Rc<RefCell<usize>>,
+  }
+
+  impl Read for SlowStream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+      if self.incoming.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::WouldBlock, "empty"));
+      }
+      let n = buffer.len().min(self.incoming.len());
+      buffer[0..n].copy_from_slice(&self.incoming[0..n]);
+      self.incoming.drain(0..n);
+      return Ok(n);
+    }
+  }
+
+  impl Write for SlowStream {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+      let mut room = self.room.borrow_mut();
+      if *room == 0 {
+        return Err(io::Error::new(io::ErrorKind::WouldBlock, "full"));
+      }
+      let n = data.len().min(*room);
+      *room -= n;
+      self.written.borrow_mut().extend_from_slice(&data[0..n]);
+      return Ok(n);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      return Ok(());
+    }
+  }
+
+  impl LinkStream for SlowStream {
+    fn set_nonblocking(&self, _flag: bool) -> io::Result<()> {
+      return Ok(());
+    }
+
+    fn set_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+      return Ok(());
+    }
+  }
+
+  fn poll_until<D: SerialDevice>(device: &mut D, byte: u8) -> u8 {
+    for _ in 0..1000 {
+      if let Some(value) = device.poll(byte) {
+        return value;
+      }
+      thread::sleep(Duration::from_millis(1));
+    }
+    panic!("transfer never arrived");
+  }
+
+  fn exchange_when_ready<D: SerialDevice>(device: &mut D, byte: u8) -> u8 {
+    for _ in 0..1000 {
+      if device.can_exchange() {
+        return device.exchange(byte);
+      }
+      thread::sleep(Duration::from_millis(1));
+    }
+    panic!("other side never armed");
+  }
+
+  #[test]
+  fn exchanges_over_localhost() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let slave_thread = thread::spawn(move || {
+      let mut slave = connect_tcp(addr).unwrap();
+      let first = poll_until(&mut slave, 0x34);
+      let second = poll_until(&mut slave, 0x56);
+      return (first, second);
+    });
+    let mut master = accept_tcp(&listener).unwrap();
+    assert_eq!(exchange_when_ready(&mut master, 0x12), 0x34);
+    assert_eq!(exchange_when_ready(&mut master, 0x78), 0x56);
+    assert_eq!(slave_thread.join().unwrap(), (0x12, 0x78));
+  }
+
+  #[test]
+  fn master_waits_for_late_slave() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let slave_thread = thread::spawn(move || {
+      let mut slave = connect_tcp(addr).unwrap();
+      let first = poll_until(&mut slave, 0x34);
+      // Arm the next transfer well after the master wants to clock it
+      thread::sleep(Duration::from_millis(100));
+      let second = poll_until(&mut slave, 0x56);
+      return (first, second);
+    });
+    let mut master = accept_tcp(&listener).unwrap();
+    assert_eq!(exchange_when_ready(&mut master, 0x12), 0x34);
+    // The first publication is stale now, so the transfer is held
+    assert!(!master.can_exchange());
+    assert_eq!(exchange_when_ready(&mut master, 0x78), 0x56);
+    assert_eq!(slave_thread.join().unwrap(), (0x12, 0x78));
+  }
+
+  #[test]
+  fn holds_transfer_for_slow_slave() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let slave_thread = thread::spawn(move || {
+      let mut slave = connect_tcp(addr).unwrap();
+      let first = poll_until(&mut slave, 0x34);
+      // Well over a second late, the master must still wait for it
+      thread::sleep(Duration::from_millis(1500));
+      let second = poll_until(&mut slave, 0x56);
+      let third = poll_until(&mut slave, 0x9a);
+      return (first, second, third);
+    });
+    let mut master = accept_tcp(&listener).unwrap();
+    assert_eq!(exchange_when_ready(&mut master, 0x12), 0x34);
+    let mut waited = 0;
+    while !master.can_exchange() {
+      waited += 1;
+      assert!(waited < 5000, "slave never armed");
+      thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(master.exchange(0x78), 0x56);
+    // Both sides still agree on the sequence
+    assert_eq!(exchange_when_ready(&mut master, 0xbc), 0x9a);
+    assert_eq!(slave_thread.join().unwrap(), (0x12, 0x78, 0xbc));
+  }
+
+  #[test]
+  fn queues_what_the_socket_cant_take() {
+    let written = Rc::new(RefCell::new(Vec::new()));
+    let room = Rc::new(RefCell::new(5));
+    let stream = SlowStream {
+      incoming: b"GBLK\x02".to_vec(),
+      written: written.clone(),
+      room: room.clone(),
+    };
+    let mut link = create_socket_link(stream).ok().unwrap();
+    // The socket is full, so the publication waits
+    assert_eq!(link.poll(0x34), None);
+    assert!(link.connected);
+    assert_eq!(written.borrow().len(), 5);
+    // Then goes out in pieces as room frees up
+    *room.borrow_mut() = 2;
+    assert_eq!(link.poll(0x34), None);
+    *room.borrow_mut() = 8;
+    assert_eq!(link.poll(0x34), None);
+    assert!(link.connected);
+    assert_eq!(&written.borrow()[5..], &[MSG_READY, 0, 0x34]);
+  }
+
+  #[test]
+  fn rejects_other_versions() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let peer = thread::spawn(move || {
+      let mut stream = TcpStream::connect(addr).unwrap();
+      stream.write_all(b"GBLK\x63").unwrap();
+      let mut hello = [0; 5];
+      stream.read_exact(&mut hello).unwrap();
+    });
+    match accept_tcp(&listener) {
+      Err(err @ LinkError::VersionMismatch(0x63)) => {
+        assert_eq!(err.to_string(), "peer speaks link protocol 99, expected 2");
+      },
+      _ => panic!("expected a version mismatch"),
+    }
+    peer.join().unwrap();
+  }
+
+  #[test]
+  fn gives_up_on_silent_peer() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (done, wait) = mpsc::channel::<()>();
+    let peer = thread::spawn(move || {
+      // Connects but never says hello
+      let _stream = TcpStream::connect(addr).unwrap();
+      wait.recv().unwrap();
+    });
+    match accept_tcp(&listener) {
+      Err(LinkError::Io(_)) => (),
+      _ => panic!("expected the handshake to time out"),
+    }
+    done.send(()).unwrap();
+    peer.join().unwrap();
+  }
+
+  #[test]
+  fn disconnected_link_reads_ff() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let peer = thread::spawn(move || {
+      connect_tcp(addr).unwrap();
+    });
+    let mut link = accept_tcp(&listener).unwrap();
+    peer.join().unwrap();
+    for _ in 0..1000 {
+      link.receive();
+      if !link.connected {
+        break;
+      }
+      thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(link.exchange(0x12), 0xff);
+  }
+}
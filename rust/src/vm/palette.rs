@@ -0,0 +1,169 @@
+// Colors are stored as 0xRRGGBB, lightest shade first
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Layer {
+  Background,
+  Object0,
+  Object1,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Preset {
+  OriginalGreen,
+  PocketGray,
+  Light,
+  HighContrast,
+  SuperGameBoy,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PaletteError {
+  InvalidFormat,
+  InvalidColor,
+  TooFewColors,
+}
+
+pub fn layer_from_index(index: u8) -> Option<Layer> {
+  return match index {
+    0 => Some(Layer::Background),
+    1 => Some(Layer::Object0),
+    2 => Some(Layer::Object1),
+    _ => None,
+  };
+}
+
+pub fn preset_from_index(index: u8) -> Option<Preset> {
+  return match index {
+    0 => Some(Preset::OriginalGreen),
+    1 => Some(Preset::PocketGray),
+    2 => Some(Preset::Light),
+    3 => Some(Preset::HighContrast),
+    4 => Some(Preset::SuperGameBoy),
+    _ => None,
+  };
+}
+
+pub fn preset_colors(preset: Preset) -> [u32; 4] {
+  return match preset {
+    Preset::OriginalGreen => [0x9bbc0f, 0x8bac0f, 0x306230, 0x0f380f],
+    Preset::PocketGray => [0xc4cfa1, 0x8b956d, 0x4d533c, 0x1f1f1f],
+    Preset::Light => [0x00b581, 0x009a71, 0x00694a, 0x004f3b],
+    Preset::HighContrast => [0xffffff, 0xaaaaaa, 0x555555, 0x000000],
+    // The SGB's palette before a game sends its own
+    Preset::SuperGameBoy => [0xf7e7c6, 0xd68c4a, 0xa53121, 0x311852],
+  };
+}
+
+// Expands a CGB BGR555 color
+pub fn cgb_color_to_rgb(color: u16) -> u32 {
+  let r = (color & 0x1f) as u32;
+  let g = ((color >> 5) & 0x1f) as u32;
+  let b = ((color >> 10) & 0x1f) as u32;
+  let expand = |c: u32| (c << 3) | (c >> 2);
+  return (expand(r) << 16) | (expand(g) << 8) | expand(b);
+}
+
+// Parses either a JASC-PAL text file or raw RGB triplets. A file with four
+// colors applies to every layer; with twelve, the colors are split into
+// BG, OBP0 and OBP1 in that order.
+pub fn parse_palette_file(data: &[u8]) -> Result<[[u32; 4]; 3], PaletteError> {
+  let colors = if data.starts_with(b"JASC-PAL") {
+    parse_jasc(data)?
+  } else {
+    parse_raw(data)?
+  };
+  if colors.len() < 4 {
+    return Err(PaletteError::TooFewColors);
+  }
+  let mut palettes = [[0; 4]; 3];
+  for layer in 0..3 {
+    for shade in 0..4 {
+      palettes[layer][shade] = if colors.len() >= 12 {
+        colors[layer * 4 + shade]
+      } else {
+        colors[shade]
+      };
+    }
+  }
+  return Ok(palettes);
+}
+
+fn parse_raw(data: &[u8]) -> Result<Vec<u32>, PaletteError> {
+  if data.len() % 3 != 0 {
+    return Err(PaletteError::InvalidFormat);
+  }
+  let mut colors = Vec::new();
+  for rgb in data.chunks(3) {
+    colors.push(((rgb[0] as u32) << 16) | ((rgb[1] as u32) << 8) | (rgb[2] as u32));
+  }
+  return Ok(colors);
+}
+
+fn parse_jasc(data: &[u8]) -> Result<Vec<u32>, PaletteError> {
+  let text = match ::std::str::from_utf8(data) {
+    Ok(t) => t,
+    Err(_) => return Err(PaletteError::InvalidFormat),
+  };
+  let mut lines = text.lines().map(|l| l.trim()).filter(|l| l.len() > 0);
+  // Header, version, and color count
+  lines.next();
+  if lines.next() != Some("0100") {
+    return Err(PaletteError::InvalidFormat);
+  }
+  let count = match lines.next().and_then(|l| l.parse::<usize>().ok()) {
+    Some(c) => c,
+    None => return Err(PaletteError::InvalidFormat),
+  };
+  let mut colors = Vec::new();
+  for line in lines.take(count) {
+    let mut rgb = 0;
+    let mut components = 0;
+    for part in line.split_whitespace() {
+      let value = match part.parse::<u8>() {
+        Ok(v) => v,
+        Err(_) => return Err(PaletteError::InvalidColor),
+      };
+      rgb = (rgb << 8) | (value as u32);
+      components += 1;
+    }
+    if components != 3 {
+      return Err(PaletteError::InvalidColor);
+    }
+    colors.push(rgb);
+  }
+  if colors.len() < count {
+    return Err(PaletteError::TooFewColors);
+  }
+  return Ok(colors);
+}
+
+#[cfg(test)]
+mod tests {
+  use vm::palette::parse_palette_file;
+  use vm::palette::PaletteError;
+
+  #[test]
+  fn raw_palette() {
+    let data = [0xff, 0xff, 0xff, 0xaa, 0xaa, 0xaa, 0x55, 0x55, 0x55, 0, 0, 0];
+    let palettes = parse_palette_file(&data).unwrap();
+    assert_eq!(palettes[0], [0xffffff, 0xaaaaaa, 0x555555, 0]);
+    assert_eq!(palettes[2], [0xffffff, 0xaaaaaa, 0x555555, 0]);
+    assert_eq!(parse_palette_file(&data[0..9]), Err(PaletteError::TooFewColors));
+    assert_eq!(parse_palette_file(&data[0..8]), Err(PaletteError::InvalidFormat));
+  }
+
+  #[test]
+  fn jasc_palette() {
+    let mut text = String::from("JASC-PAL\r\n0100\r\n12\r\n");
+    for i in 0..12 {
+      text.push_str(&format!("{} 0 {}\r\n", i, 255 - i));
+    }
+    let palettes = parse_palette_file(text.as_bytes()).unwrap();
+    assert_eq!(palettes[0][0], 0x0000ff);
+    assert_eq!(palettes[1][0], 0x0400fb);
+    assert_eq!(palettes[2][3], 0x0b00f4);
+
+    let bad = "JASC-PAL\n0100\n4\n0 0 0\n1 1\n2 2 2\n3 3 3\n";
+    assert_eq!(parse_palette_file(bad.as_bytes()), Err(PaletteError::InvalidColor));
+  }
+}
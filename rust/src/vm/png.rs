@@ -0,0 +1,101 @@
+// Minimal PNG encoder for 8-bit grayscale images. The image data is stored
+// uncompressed inside the zlib stream, which every decoder accepts.
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+
+// Largest stored deflate block
+const BLOCK_SIZE: usize = 0xffff;
+
+pub fn encode_grayscale(width: u32, height: u32, pixels: &[u8]) -> Vec<u8> {
+  let mut png = Vec::new();
+  png.extend_from_slice(&SIGNATURE);
+
+  let mut header = Vec::new();
+  header.extend_from_slice(&u32_be(width));
+  header.extend_from_slice(&u32_be(height));
+  // 8 bits per pixel, grayscale, default compression, filter and interlace
+  header.extend_from_slice(&[8, 0, 0, 0, 0]);
+  write_chunk(&mut png, b"IHDR", &header);
+
+  // Each row starts with its filter type, always none here
+  let mut raw = Vec::with_capacity(((width + 1) * height) as usize);
+  for row in pixels.chunks(width as usize).take(height as usize) {
+    raw.push(0);
+    raw.extend_from_slice(row);
+  }
+  write_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+  write_chunk(&mut png, b"IEND", &[]);
+  return png;
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+  png.extend_from_slice(&u32_be(data.len() as u32));
+  let start = png.len();
+  png.extend_from_slice(kind);
+  png.extend_from_slice(data);
+  let crc = crc32(&png[start..]);
+  png.extend_from_slice(&u32_be(crc));
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+  let mut out = vec![0x78, 0x01];
+  let blocks = if data.is_empty() { 1 } else { (data.len() + BLOCK_SIZE - 1) / BLOCK_SIZE };
+  for i in 0..blocks {
+    let start = i * BLOCK_SIZE;
+    let end = (start + BLOCK_SIZE).min(data.len());
+    let len = (end - start) as u16;
+    out.push(if i == blocks - 1 { 1 } else { 0 });
+    out.push(len as u8);
+    out.push((len >> 8) as u8);
+    out.push(!len as u8);
+    out.push((!len >> 8) as u8);
+    out.extend_from_slice(&data[start..end]);
+  }
+  out.extend_from_slice(&u32_be(adler32(data)));
+  return out;
+}
+
+fn u32_be(value: u32) -> [u8; 4] {
+  return [(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8];
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+  let mut crc = 0xffffffff;
+  for byte in data {
+    crc ^= *byte as u32;
+    for _ in 0..8 {
+      crc = if crc & 1 > 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+    }
+  }
+  return !crc;
+}
+
+fn adler32(data: &[u8]) -> u32 {
+  let mut a = 1;
+  let mut b = 0;
+  for byte in data {
+    a = (a + *byte as u32) % 65521;
+    b = (b + a) % 65521;
+  }
+  return (b << 16) | a;
+}
+
+#[cfg(test)]
+mod tests {
+  use vm::png::crc32;
+  use vm::png::encode_grayscale;
+
+  #[test]
+  fn encodes_grayscale_image() {
+    assert_eq!(crc32(b"IEND"), 0xae426082);
+    let png = encode_grayscale(2, 2, &[0x00, 0xff, 0x55, 0xaa]);
+    assert_eq!(&png[0..8], &[0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a]);
+    assert_eq!(&png[12..16], b"IHDR");
+    assert_eq!(&png[16..24], &[0, 0, 0, 2, 0, 0, 0, 2]);
+    // Stored block holding two filtered rows
+    let idat = 8 + 25;
+    assert_eq!(&png[idat + 4..idat + 8], b"IDAT");
+    assert_eq!(&png[idat + 10..idat + 21], &[0x01, 6, 0, 0xf9, 0xff, 0, 0x00, 0xff, 0, 0x55, 0xaa]);
+    assert_eq!(&png[png.len() - 12..], &[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]);
+  }
+}
@@ -0,0 +1,398 @@
+use std::any::Any;
+use vm::png;
+use vm::serial::SerialDevice;
+
+pub const PRINT_WIDTH: usize = 160;
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_STATUS: u8 = 0x0f;
+
+// Status bits
+const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+const STATUS_PRINTING: u8 = 0x02;
+const STATUS_IMAGE_FULL: u8 = 0x04;
+const STATUS_UNPROCESSED: u8 = 0x08;
+
+// A DATA packet holds two rows of 20 tiles
+const BAND_SIZE: usize = 0x280;
+const BUFFER_SIZE: usize = BAND_SIZE * 9;
+
+// Status requests answered as busy after a print, so games see it working
+const PRINT_POLLS: u8 = 8;
+
+// Print palette used when a game sends 0, the same as BGP 0xe4
+const DEFAULT_PALETTE: u8 = 0xe4;
+
+// Grayscale shades, lightest first
+const SHADES: [u8; 4] = [0xff, 0xaa, 0x55, 0x00];
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum PacketState {
+  Magic1,
+  Magic2,
+  Command,
+  Compression,
+  LengthLow,
+  LengthHigh,
+  Data,
+  ChecksumLow,
+  ChecksumHigh,
+  Alive,
+  Status,
+}
+
+// A finished print, one grayscale byte per pixel
+pub struct Print {
+  pub width: usize,
+  pub height: usize,
+  pub pixels: Vec<u8>,
+}
+
+impl Print {
+  pub fn to_png(&self) -> Vec<u8> {
+    return png::encode_grayscale(self.width as u32, self.height as u32, &self.pixels);
+  }
+}
+
+pub struct Printer {
+  state: PacketState,
+  command: u8,
+  compressed: bool,
+  length: u16,
+  packet: Vec<u8>,
+  checksum: u16,
+  received_checksum: u16,
+
+  status: u8,
+  busy_polls: u8,
+  // Tile data waiting for a PRINT command
+  buffer: Vec<u8>,
+  // A print with no bottom margin continues on the next one
+  current: Option<Print>,
+
+  // Finished prints, oldest first
+  pub prints: Vec<Print>,
+  // Last print taken by the host, exposed through a pointer
+  pub output: Vec<u8>,
+}
+
+pub fn create_printer() -> Printer {
+  return Printer {
+    state: PacketState::Magic1,
+    command: 0,
+    compressed: false,
+    length: 0,
+    packet: Vec::new(),
+    checksum: 0,
+    received_checksum: 0,
+
+    status: 0,
+    busy_polls: 0,
+    buffer: Vec::new(),
+    current: None,
+
+    prints: Vec::new(),
+    output: Vec::new(),
+  };
+}
+
+impl Printer {
+  // Moves the oldest finished print into `output`, returning its height
+  pub fn take_print(&mut self) -> usize {
+    if self.prints.is_empty() {
+      return 0;
+    }
+    let print = self.prints.remove(0);
+    self.output = print.pixels;
+    return print.height;
+  }
+
+  // Moves the oldest finished print into `output` as a PNG file, returning
+  // its size in bytes
+  pub fn take_png(&mut self) -> usize {
+    if self.prints.is_empty() {
+      return 0;
+    }
+    let print = self.prints.remove(0);
+    self.output = print.to_png();
+    return self.output.len();
+  }
+
+  fn receive(&mut self, byte: u8) -> u8 {
+    let mut response = 0x00;
+    self.state = match self.state {
+      PacketState::Magic1 => {
+        if byte == 0x88 { PacketState::Magic2 } else { PacketState::Magic1 }
+      },
+      PacketState::Magic2 => {
+        if byte == 0x33 { PacketState::Command } else { PacketState::Magic1 }
+      },
+      PacketState::Command => {
+        self.command = byte;
+        self.checksum = byte as u16;
+        PacketState::Compression
+      },
+      PacketState::Compression => {
+        self.compressed = byte & 0x1 > 0;
+        self.checksum = self.checksum.wrapping_add(byte as u16);
+        PacketState::LengthLow
+      },
+      PacketState::LengthLow => {
+        self.length = byte as u16;
+        self.checksum = self.checksum.wrapping_add(byte as u16);
+        PacketState::LengthHigh
+      },
+      PacketState::LengthHigh => {
+        self.length |= (byte as u16) << 8;
+        self.checksum = self.checksum.wrapping_add(byte as u16);
+        self.packet.clear();
+        if self.length > 0 { PacketState::Data } else { PacketState::ChecksumLow }
+      },
+      PacketState::Data => {
+        self.packet.push(byte);
+        self.checksum = self.checksum.wrapping_add(byte as u16);
+        if self.packet.len() >= self.length as usize {
+          PacketState::ChecksumLow
+        } else {
+          PacketState::Data
+        }
+      },
+      PacketState::ChecksumLow => {
+        self.received_checksum = byte as u16;
+        PacketState::ChecksumHigh
+      },
+      PacketState::ChecksumHigh => {
+        self.received_checksum |= (byte as u16) << 8;
+        PacketState::Alive
+      },
+      PacketState::Alive => {
+        response = 0x81;
+        PacketState::Status
+      },
+      PacketState::Status => {
+        self.run_command();
+        response = self.status;
+        PacketState::Magic1
+      },
+    };
+    return response;
+  }
+
+  fn run_command(&mut self) {
+    if self.received_checksum != self.checksum {
+      self.status |= STATUS_CHECKSUM_ERROR;
+      return;
+    }
+    self.status &= !STATUS_CHECKSUM_ERROR;
+    match self.command {
+      COMMAND_INIT => {
+        self.buffer.clear();
+        self.status = 0;
+        self.busy_polls = 0;
+      },
+      COMMAND_DATA => {
+        if self.packet.is_empty() {
+          // An empty packet marks the end of the image
+          if !self.buffer.is_empty() {
+            self.status |= STATUS_IMAGE_FULL;
+          }
+        } else {
+          let data = if self.compressed {
+            decompress(&self.packet)
+          } else {
+            self.packet.clone()
+          };
+          let room = BUFFER_SIZE - self.buffer.len();
+          self.buffer.extend_from_slice(&data[0..data.len().min(room)]);
+          self.status |= STATUS_UNPROCESSED;
+        }
+      },
+      COMMAND_PRINT => {
+        if self.packet.len() >= 4 {
+          let margins = self.packet[1];
+          // Printers treat a palette of 0 as the default
+          let palette = if self.packet[2] == 0 { DEFAULT_PALETTE } else { self.packet[2] };
+          self.print(margins, palette);
+          self.status = (self.status & !(STATUS_UNPROCESSED | STATUS_IMAGE_FULL)) | STATUS_PRINTING;
+          self.busy_polls = PRINT_POLLS;
+        }
+      },
+      COMMAND_STATUS => {
+        if self.busy_polls > 0 {
+          self.busy_polls -= 1;
+          if self.busy_polls == 0 {
+            self.status &= !STATUS_PRINTING;
+          }
+        }
+      },
+      _ => (),
+    }
+  }
+
+  fn print(&mut self, margins: u8, palette: u8) {
+    let rows = self.buffer.len() / (BAND_SIZE / 2) * 8;
+    let mut pixels = vec![0; PRINT_WIDTH * rows];
+    for y in 0..rows {
+      for x in 0..PRINT_WIDTH {
+        let tile = (y / 8) * 20 + x / 8;
+        let offset = tile * 16 + (y % 8) * 2;
+        let bit = 7 - (x % 8);
+        let color = ((self.buffer[offset] >> bit) & 1) | (((self.buffer[offset + 1] >> bit) & 1) << 1);
+        let shade = (palette >> (color * 2)) & 0x3;
+        pixels[y * PRINT_WIDTH + x] = SHADES[shade as usize];
+      }
+    }
+    self.buffer.clear();
+
+    let mut print = match self.current.take() {
+      Some(p) => p,
+      None => Print { width: PRINT_WIDTH, height: 0, pixels: Vec::new() },
+    };
+    print.pixels.extend_from_slice(&pixels);
+    print.height += rows;
+    // Without a bottom margin the paper isn't fed out, so the next print
+    // lands on the same sheet
+    if margins & 0xf == 0 {
+      self.current = Some(print);
+    } else {
+      self.prints.push(print);
+    }
+  }
+}
+
+// Runs are a control byte with bit 7 set followed by one byte repeated
+// (control & 0x7f) + 2 times, literals are control + 1 bytes copied as is
+fn decompress(data: &[u8]) -> Vec<u8> {
+  let mut out = Vec::new();
+  let mut i = 0;
+  while i < data.len() {
+    let control = data[i];
+    i += 1;
+    if control & 0x80 > 0 {
+      if i >= data.len() {
+        break;
+      }
+      let count = ((control & 0x7f) as usize) + 2;
+      for _ in 0..count {
+        out.push(data[i]);
+      }
+      i += 1;
+    } else {
+      let count = (control as usize) + 1;
+      let end = (i + count).min(data.len());
+      out.extend_from_slice(&data[i..end]);
+      i = end;
+    }
+  }
+  return out;
+}
+
+impl SerialDevice for Printer {
+  fn exchange(&mut self, byte: u8) -> u8 {
+    return self.receive(byte);
+  }
+
+  fn as_any(&mut self) -> &mut dyn Any {
+    return self;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use vm::printer::create_printer;
+  use vm::printer::Printer;
+  use vm::serial::SerialDevice;
+
+  fn send_packet(printer: &mut Printer, command: u8, compressed: bool, data: &[u8]) -> (u8, u8) {
+    let mut bytes = vec![0x88, 0x33, command, if compressed { 1 } else { 0 }];
+    bytes.push(data.len() as u8);
+    bytes.push((data.len() >> 8) as u8);
+    bytes.extend_from_slice(data);
+    let checksum = bytes[2..].iter().fold(0u16, |sum, b| sum.wrapping_add(*b as u16));
+    bytes.push(checksum as u8);
+    bytes.push((checksum >> 8) as u8);
+    for byte in bytes {
+      assert_eq!(printer.exchange(byte), 0x00);
+    }
+    let alive = printer.exchange(0x00);
+    let status = printer.exchange(0x00);
+    return (alive, status);
+  }
+
+  #[test]
+  fn prints_uncompressed_band() {
+    let mut printer = create_printer();
+    assert_eq!(send_packet(&mut printer, 0x01, false, &[]), (0x81, 0x00));
+    // First tile row is color 3 on its top line, everything else color 0
+    let mut band = vec![0; 0x280];
+    band[0] = 0xff;
+    band[1] = 0xff;
+    assert_eq!(send_packet(&mut printer, 0x04, false, &band), (0x81, 0x08));
+    assert_eq!(send_packet(&mut printer, 0x04, false, &[]), (0x81, 0x0c));
+    // One sheet, 3 line margin after, BGP style palette 0xe4
+    assert_eq!(send_packet(&mut printer, 0x02, false, &[1, 0x03, 0xe4, 0x40]), (0x81, 0x02));
+    for _ in 0..7 {
+      assert_eq!(send_packet(&mut printer, 0x0f, false, &[]).1, 0x02);
+    }
+    assert_eq!(send_packet(&mut printer, 0x0f, false, &[]).1, 0x00);
+
+    assert_eq!(printer.prints.len(), 1);
+    assert_eq!(printer.take_print(), 16);
+    assert_eq!(printer.output.len(), 160 * 16);
+    assert_eq!(&printer.output[0..9], &[0, 0, 0, 0, 0, 0, 0, 0, 0xff]);
+    assert_eq!(printer.output[160], 0xff);
+    assert_eq!(printer.take_print(), 0);
+  }
+
+  #[test]
+  fn decompresses_and_stitches_prints() {
+    let mut printer = create_printer();
+    send_packet(&mut printer, 0x01, false, &[]);
+    // 0x280 bytes of 0x55: four runs of 128, a run of 126 and a 2 byte literal
+    let mut data = Vec::new();
+    for _ in 0..4 {
+      data.extend_from_slice(&[0xfe, 0x55]);
+    }
+    data.extend_from_slice(&[0xfc, 0x55, 0x01, 0x55, 0x55]);
+    assert_eq!(send_packet(&mut printer, 0x04, true, &data), (0x81, 0x08));
+    // No bottom margin: the next band continues the same print
+    send_packet(&mut printer, 0x02, false, &[1, 0x10, 0xe4, 0x40]);
+    assert!(printer.prints.is_empty());
+    send_packet(&mut printer, 0x04, false, &vec![0; 0x280]);
+    send_packet(&mut printer, 0x02, false, &[1, 0x03, 0xe4, 0x40]);
+    assert_eq!(printer.prints.len(), 1);
+    assert_eq!(printer.prints[0].height, 32);
+    // 0x55 in both planes is color 3 on alternating pixels
+    assert_eq!(&printer.prints[0].pixels[0..2], &[0xff, 0x00]);
+    assert_eq!(printer.prints[0].pixels[160 * 16 + 1], 0xff);
+    let size = printer.take_png();
+    assert_eq!(size, printer.output.len());
+    assert_eq!(&printer.output[1..4], b"PNG");
+    assert_eq!(printer.take_png(), 0);
+  }
+
+  #[test]
+  fn zero_palette_prints_default_shades() {
+    let mut printer = create_printer();
+    send_packet(&mut printer, 0x01, false, &[]);
+    let mut band = vec![0; 0x280];
+    band[0] = 0xff;
+    band[1] = 0xff;
+    send_packet(&mut printer, 0x04, false, &band);
+    send_packet(&mut printer, 0x02, false, &[1, 0x03, 0x00, 0x40]);
+    assert_eq!(printer.take_print(), 16);
+    assert_eq!(&printer.output[7..9], &[0x00, 0xff]);
+  }
+
+  #[test]
+  fn rejects_bad_checksum() {
+    let mut printer = create_printer();
+    for byte in &[0x88, 0x33, 0x0f, 0x00, 0x00, 0x00, 0x00, 0x00] {
+      printer.exchange(*byte);
+    }
+    assert_eq!(printer.exchange(0x00), 0x81);
+    assert_eq!(printer.exchange(0x00), 0x01);
+  }
+}
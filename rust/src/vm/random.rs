@@ -0,0 +1,104 @@
+// Power-on contents of RAM. Real hardware doesn't clear memory, so code that
+// reads before writing gets whatever the chips settled on.
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RamInit {
+  Zero,
+  Random,
+  // Approximates DMG WRAM: alternating blocks of 0x00 and 0xff with a few
+  // flipped bits
+  Hardware,
+}
+
+pub fn ram_init_from_index(index: u8) -> Option<RamInit> {
+  return match index {
+    0 => Some(RamInit::Zero),
+    1 => Some(RamInit::Random),
+    2 => Some(RamInit::Hardware),
+    _ => None,
+  };
+}
+
+// xorshift32, so the same seed always gives the same memory
+pub struct Rng {
+  state: u32,
+}
+
+pub fn create_rng(seed: u32) -> Rng {
+  // Zero would get stuck
+  return Rng { state: if seed == 0 { 0x6d2b79f5 } else { seed } };
+}
+
+impl Rng {
+  pub fn next(&mut self) -> u32 {
+    let mut x = self.state;
+    x ^= x << 13;
+    x ^= x >> 17;
+    x ^= x << 5;
+    self.state = x;
+    return x;
+  }
+
+  pub fn next_byte(&mut self) -> u8 {
+    return (self.next() >> 24) as u8;
+  }
+
+  pub fn fill(&mut self, ram: &mut [u8], init: RamInit) {
+    for i in 0..ram.len() {
+      ram[i] = match init {
+        RamInit::Zero => 0,
+        RamInit::Random => self.next_byte(),
+        RamInit::Hardware => {
+          let block = if (i / 8) % 2 == 0 { 0x00 } else { 0xff };
+          // Roughly one byte in 32 has a stray bit
+          let noise = self.next();
+          if noise & 0x1f == 0 { block ^ (1 << ((noise >> 8) & 0x7)) } else { block }
+        },
+      };
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use vm::cpu::Register16;
+  use vm::cpu::Register8;
+  use vm::create_vm;
+  use vm::random::create_rng;
+  use vm::random::RamInit;
+
+  #[test]
+  fn fills_are_reproducible() {
+    let mut a = [0; 64];
+    let mut b = [0; 64];
+    create_rng(1234).fill(&mut a, RamInit::Random);
+    create_rng(1234).fill(&mut b, RamInit::Random);
+    assert_eq!(&a[..], &b[..]);
+    create_rng(4321).fill(&mut b, RamInit::Random);
+    assert!(&a[..] != &b[..]);
+
+    let mut ram = [0; 0x400];
+    create_rng(7).fill(&mut ram, RamInit::Hardware);
+    let ones = ram[0..8].iter().filter(|b| **b != 0).count();
+    assert!(ones < 4);
+    create_rng(7).fill(&mut ram, RamInit::Zero);
+    assert!(ram.iter().all(|b| *b == 0));
+  }
+
+  #[test]
+  fn power_on_garbles_registers_for_boot_rom() {
+    let mut vm = create_vm();
+    vm.power_on(RamInit::Random, 1234);
+    vm.reset_with_boot_rom(0x100);
+    let registers = [
+      Register8::A, Register8::B, Register8::C, Register8::D,
+      Register8::E, Register8::H, Register8::L,
+    ];
+    assert!(registers.iter().any(|r| vm.cpu.get_register_8(*r) != 0));
+    assert_eq!(vm.cpu.get_register_16(Register16::PC), 0);
+
+    // Only the first reset after powering on
+    vm.reset_with_boot_rom(0x100);
+    assert!(registers.iter().all(|r| vm.cpu.get_register_8(*r) == 0));
+  }
+}
@@ -0,0 +1,286 @@
+use std::any::Any;
+use std::mem;
+
+// The internal clock shifts one bit every 512 cycles (8192 Hz)
+const BIT_TIME: u32 = 512;
+// CGB fast clock, selected by bit 1 of SC
+const FAST_BIT_TIME: u32 = 16;
+
+// Anything plugged into the other end of the link port
+pub trait SerialDevice {
+  // A transfer clocked by the Game Boy: the device receives our byte and
+  // answers with its own
+  fn exchange(&mut self, byte: u8) -> u8;
+
+  // Devices that can't answer straight away return false, which holds the
+  // Game Boy's clock until they can
+  fn can_exchange(&mut self) -> bool {
+    return true;
+  }
+
+  // Called while the Game Boy waits on an external clock with `byte` in SB.
+  // Returns the device's byte once it has clocked a full transfer.
+  fn poll(&mut self, _byte: u8) -> Option<u8> {
+    return None;
+  }
+
+  // Lets hosts get back to the concrete device
+  fn as_any(&mut self) -> &mut dyn Any;
+}
+
+pub struct Serial {
+  pub data: u8,
+  pub control: u8,
+
+  pub device: Option<Box<dyn SerialDevice>>,
+
+  // Byte being shifted in during an internally clocked transfer
+  incoming: u8,
+  bits_left: u8,
+  time: u32,
+  // Set while the device hasn't answered the transfer yet
+  waiting: bool,
+
+  // Bytes sent by the Game Boy, useful for test ROMs that report over serial
+  pub capture_output: bool,
+  pub output: Vec<u8>,
+  // Output moved out by take_output, kept for hosts to read
+  pub taken_output: Vec<u8>,
+}
+
+pub fn create_serial() -> Serial {
+  return Serial {
+    data: 0,
+    control: 0,
+
+    device: None,
+
+    incoming: 0xff,
+    bits_left: 0,
+    time: 0,
+    waiting: false,
+
+    capture_output: false,
+    output: Vec::new(),
+    taken_output: Vec::new(),
+  };
+}
+
+impl Serial {
+  pub fn get_control(&self, cgb_mode: bool) -> u8 {
+    let unused = if cgb_mode { 0x7c } else { 0x7e };
+    return self.control | unused;
+  }
+
+  pub fn set_data(&mut self, value: u8) {
+    self.data = value;
+  }
+
+  pub fn set_control(&mut self, value: u8, cgb_mode: bool) {
+    self.control = if cgb_mode { value & 0x83 } else { value & 0x81 };
+    if value & 0x81 == 0x81 {
+      self.start_internal_transfer();
+    } else {
+      self.bits_left = 0;
+    }
+  }
+
+  fn start_internal_transfer(&mut self) {
+    if self.capture_output {
+      self.output.push(self.data);
+    }
+    self.bits_left = 8;
+    self.time = 0;
+    self.waiting = true;
+    self.exchange();
+  }
+
+  fn exchange(&mut self) {
+    let byte = self.data;
+    // Without a cable, the line floats high and every bit reads as 1
+    self.incoming = match self.device {
+      Some(ref mut device) => {
+        if !device.can_exchange() {
+          return;
+        }
+        device.exchange(byte)
+      },
+      None => 0xff,
+    };
+    self.waiting = false;
+  }
+
+  // Moves the bytes captured since the last call into `taken_output`,
+  // returning how many there are
+  pub fn take_output(&mut self) -> usize {
+    self.taken_output = mem::replace(&mut self.output, Vec::new());
+    return self.taken_output.len();
+  }
+
+  // CPU cycles per bit on the internal clock
+  pub fn bit_time(&self) -> u32 {
+    return if self.control & 0x2 > 0 { FAST_BIT_TIME } else { BIT_TIME };
+  }
+
+  pub fn is_transferring(&self) -> bool {
+    return self.control & 0x80 > 0;
+  }
+
+  // Returns true when a transfer completes and the serial interrupt fires
+  pub fn add_time(&mut self, time: u8) -> bool {
+    if !self.is_transferring() {
+      return false;
+    }
+    if self.control & 0x1 == 0 {
+      return self.poll_external();
+    }
+    if self.waiting {
+      self.exchange();
+      if self.waiting {
+        return false;
+      }
+    }
+    let bit_time = self.bit_time();
+    self.time += time as u32;
+    while self.time >= bit_time && self.bits_left > 0 {
+      self.time -= bit_time;
+      self.bits_left -= 1;
+      let bit = (self.incoming >> self.bits_left) & 1;
+      self.data = (self.data << 1) | bit;
+    }
+    if self.bits_left == 0 {
+      self.control &= 0x7f;
+      return true;
+    }
+    return false;
+  }
+
+  fn poll_external(&mut self) -> bool {
+    let byte = self.data;
+    let incoming = match self.device {
+      Some(ref mut device) => device.poll(byte),
+      None => None,
+    };
+    return match incoming {
+      Some(value) => {
+        if self.capture_output {
+          self.output.push(byte);
+        }
+        self.data = value;
+        self.control &= 0x7f;
+        true
+      },
+      None => false,
+    };
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::any::Any;
+  use vm::serial::create_serial;
+  use vm::serial::SerialDevice;
+
+  struct Echo {
+    received: Vec<u8>,
+    clocked: Option<u8>,
+    busy: bool,
+  }
+
+  impl SerialDevice for Echo {
+    fn exchange(&mut self, byte: u8) -> u8 {
+      self.received.push(byte);
+      return byte ^ 0xff;
+    }
+
+    fn can_exchange(&mut self) -> bool {
+      return !self.busy;
+    }
+
+    fn poll(&mut self, byte: u8) -> Option<u8> {
+      let value = self.clocked.take();
+      if value.is_some() {
+        self.received.push(byte);
+      }
+      return value;
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+      return self;
+    }
+  }
+
+  #[test]
+  fn no_cable_reads_ff() {
+    let mut serial = create_serial();
+    serial.capture_output = true;
+    serial.set_data(0x42);
+    serial.set_control(0x81, false);
+    assert_eq!(serial.get_control(false), 0xff);
+    for _ in 0..128 {
+      assert!(!serial.add_time(16));
+    }
+    // Half the bits have been shifted in
+    assert_eq!(serial.data, 0x2f);
+    for _ in 0..127 {
+      assert!(!serial.add_time(16));
+    }
+    assert!(serial.add_time(16));
+    assert_eq!(serial.data, 0xff);
+    assert_eq!(serial.get_control(false), 0x7f);
+    assert_eq!(serial.output, vec![0x42]);
+    assert_eq!(serial.take_output(), 1);
+    assert_eq!(serial.taken_output, vec![0x42]);
+    assert!(serial.output.is_empty());
+    assert_eq!(serial.take_output(), 0);
+  }
+
+  #[test]
+  fn internal_clock_exchanges_with_device() {
+    let mut serial = create_serial();
+    serial.device = Some(Box::new(Echo { received: Vec::new(), clocked: None, busy: false }));
+    serial.set_data(0x0f);
+    serial.set_control(0x83, true);
+    assert!(!serial.add_time(64));
+    assert!(serial.add_time(64));
+    assert_eq!(serial.data, 0xf0);
+    let echo = serial.device.as_mut().unwrap().as_any().downcast_mut::<Echo>().unwrap();
+    assert_eq!(echo.received, vec![0x0f]);
+  }
+
+  #[test]
+  fn internal_clock_waits_for_busy_device() {
+    let mut serial = create_serial();
+    serial.device = Some(Box::new(Echo { received: Vec::new(), clocked: None, busy: true }));
+    serial.set_data(0x0f);
+    serial.set_control(0x81, false);
+    for _ in 0..1000 {
+      assert!(!serial.add_time(255));
+    }
+    assert_eq!(serial.data, 0x0f);
+    serial.device.as_mut().unwrap().as_any().downcast_mut::<Echo>().unwrap().busy = false;
+    for _ in 0..255 {
+      assert!(!serial.add_time(16));
+    }
+    assert!(serial.add_time(16));
+    assert_eq!(serial.data, 0xf0);
+  }
+
+  #[test]
+  fn external_clock_waits_for_device() {
+    let mut serial = create_serial();
+    serial.set_data(0x12);
+    serial.set_control(0x80, false);
+    for _ in 0..1000 {
+      assert!(!serial.add_time(255));
+    }
+    assert!(serial.is_transferring());
+
+    serial.device = Some(Box::new(Echo { received: Vec::new(), clocked: None, busy: false }));
+    assert!(!serial.add_time(4));
+    serial.device.as_mut().unwrap().as_any().downcast_mut::<Echo>().unwrap().clocked = Some(0x34);
+    assert!(serial.add_time(4));
+    assert_eq!(serial.data, 0x34);
+    assert!(!serial.is_transferring());
+  }
+}
@@ -0,0 +1,564 @@
+use vm::palette;
+
+pub const BORDER_WIDTH: usize = 256;
+pub const BORDER_HEIGHT: usize = 224;
+
+// Position of the Game Boy screen inside the border
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+
+// Default SGB palette, used until the game sends its own colors
+const DEFAULT_COLORS: [u16; 4] = [0x639e, 0x263a, 0x10d4, 0x2866];
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Mask {
+  None,
+  Freeze,
+  Black,
+  Color0,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Transfer {
+  None,
+  Palettes,
+  Tiles(u8),
+  Border,
+}
+
+pub struct Sgb {
+  pub enabled: bool,
+
+  // Packet reception over P14/P15
+  receiving: bool,
+  last_select: u8,
+  bit_count: u8,
+  packet: [u8; 16],
+  command: Vec<u8>,
+  packets_left: u8,
+
+  pub palettes: [[u16; 4]; 4],
+  system_palettes: Box<[[u16; 4]; 512]>,
+  // Palette number for each 8x8 cell of the screen
+  pub attributes: [u8; 20 * 18],
+  pub mask: Mask,
+  pending_transfer: Transfer,
+
+  border_tiles: Box<[u8; 0x2000]>,
+  border_map: Box<[u8; 0x800]>,
+  border_palettes: [[u16; 16]; 4],
+
+  pub player_count: u8,
+
+  // RGBA pixels for the border, with the screen in the middle
+  pub framebuffer: Box<[u8; BORDER_WIDTH * BORDER_HEIGHT * 4]>,
+}
+
+pub fn create_sgb() -> Sgb {
+  return Sgb {
+    enabled: false,
+
+    receiving: false,
+    last_select: 0x30,
+    bit_count: 0,
+    packet: [0; 16],
+    command: Vec::new(),
+    packets_left: 0,
+
+    palettes: [DEFAULT_COLORS; 4],
+    system_palettes: box [[0; 4]; 512],
+    attributes: [0; 20 * 18],
+    mask: Mask::None,
+    pending_transfer: Transfer::None,
+
+    border_tiles: box [0; 0x2000],
+    border_map: box [0; 0x800],
+    border_palettes: [[0; 16]; 4],
+
+    player_count: 1,
+
+    framebuffer: box [0; BORDER_WIDTH * BORDER_HEIGHT * 4],
+  };
+}
+
+impl Sgb {
+  // Receives the P14/P15 bits written to 0xff00. A low pulse on both lines
+  // resets the transfer, after that a pulse on P14 sends a 0 and a pulse on
+  // P15 sends a 1. Each packet is 128 bits followed by a 0 stop bit.
+  pub fn write_joypad(&mut self, value: u8) {
+    let select = value & 0x30;
+    let previous = self.last_select;
+    self.last_select = select;
+    if select == 0x00 {
+      self.receiving = true;
+      self.bit_count = 0;
+      self.packet = [0; 16];
+      return;
+    }
+    if !self.receiving || previous != 0x30 || select == 0x30 {
+      return;
+    }
+    let bit = if select == 0x10 { 1 } else { 0 };
+    if self.bit_count == 128 {
+      self.receiving = false;
+      if bit == 0 {
+        let packet = self.packet;
+        self.receive_packet(&packet);
+      }
+      return;
+    }
+    let byte = (self.bit_count / 8) as usize;
+    self.packet[byte] |= bit << (self.bit_count % 8);
+    self.bit_count += 1;
+  }
+
+  fn receive_packet(&mut self, packet: &[u8; 16]) {
+    if self.packets_left == 0 {
+      let length = packet[0] & 0x7;
+      if length == 0 {
+        return;
+      }
+      self.command.clear();
+      self.packets_left = length;
+    }
+    self.command.extend_from_slice(packet);
+    self.packets_left -= 1;
+    if self.packets_left == 0 {
+      let command = self.command.clone();
+      self.run_command(&command);
+    }
+  }
+
+  fn run_command(&mut self, data: &[u8]) {
+    match data[0] >> 3 {
+      0x00 => self.set_palette_pair(0, 1, data),
+      0x01 => self.set_palette_pair(2, 3, data),
+      0x02 => self.set_palette_pair(0, 3, data),
+      0x03 => self.set_palette_pair(1, 2, data),
+      0x04 => self.attr_blk(data),
+      0x05 => self.attr_lin(data),
+      0x06 => self.attr_div(data),
+      0x07 => self.attr_chr(data),
+      0x0a => self.pal_set(data),
+      0x0b => self.pending_transfer = Transfer::Palettes,
+      0x11 => {
+        self.player_count = match data[1] & 0x3 {
+          1 => 2,
+          3 => 4,
+          _ => 1,
+        };
+      },
+      0x13 => self.pending_transfer = Transfer::Tiles(data[1] & 0x1),
+      0x14 => self.pending_transfer = Transfer::Border,
+      0x17 => {
+        self.mask = match data[1] & 0x3 {
+          1 => Mask::Freeze,
+          2 => Mask::Black,
+          3 => Mask::Color0,
+          _ => Mask::None,
+        };
+      },
+      _ => (),
+    }
+  }
+
+  fn set_palette_pair(&mut self, first: usize, second: usize, data: &[u8]) {
+    let color = |i: usize| (data[i] as u16) | ((data[i + 1] as u16) << 8);
+    // Color 0 is shared by every palette
+    let shared = color(1);
+    for p in 0..4 {
+      self.palettes[p][0] = shared;
+    }
+    for c in 0..3 {
+      self.palettes[first][c + 1] = color(3 + c * 2);
+      self.palettes[second][c + 1] = color(9 + c * 2);
+    }
+  }
+
+  fn attr_blk(&mut self, data: &[u8]) {
+    let sets = (data[1] & 0x1f) as usize;
+    for set in 0..sets {
+      let offset = 2 + set * 6;
+      if offset + 6 > data.len() {
+        break;
+      }
+      let control = data[offset] & 0x7;
+      let colors = data[offset + 1];
+      let inside = colors & 0x3;
+      let mut border = (colors >> 2) & 0x3;
+      let outside = (colors >> 4) & 0x3;
+      let mut change_border = control & 0x2 != 0;
+      // With only one of inside/outside set, the border follows it
+      if control == 0x1 {
+        border = inside;
+        change_border = true;
+      } else if control == 0x4 {
+        border = outside;
+        change_border = true;
+      }
+      let x1 = data[offset + 2] & 0x1f;
+      let y1 = data[offset + 3] & 0x1f;
+      let x2 = data[offset + 4] & 0x1f;
+      let y2 = data[offset + 5] & 0x1f;
+      for y in 0..18 {
+        for x in 0..20 {
+          let in_x = x >= x1 && x <= x2;
+          let in_y = y >= y1 && y <= y2;
+          let on_border = (in_x && in_y) && (x == x1 || x == x2 || y == y1 || y == y2);
+          let cell = (y as usize) * 20 + (x as usize);
+          if on_border {
+            if change_border {
+              self.attributes[cell] = border;
+            }
+          } else if in_x && in_y {
+            if control & 0x1 != 0 {
+              self.attributes[cell] = inside;
+            }
+          } else if control & 0x4 != 0 {
+            self.attributes[cell] = outside;
+          }
+        }
+      }
+    }
+  }
+
+  fn attr_lin(&mut self, data: &[u8]) {
+    let sets = data[1] as usize;
+    for set in 0..sets {
+      if 2 + set >= data.len() {
+        break;
+      }
+      let line = data[2 + set];
+      let number = (line & 0x1f) as usize;
+      let palette = (line >> 5) & 0x3;
+      if line & 0x80 > 0 {
+        // Horizontal line
+        if number < 18 {
+          for x in 0..20 {
+            self.attributes[number * 20 + x] = palette;
+          }
+        }
+      } else if number < 20 {
+        for y in 0..18 {
+          self.attributes[y * 20 + number] = palette;
+        }
+      }
+    }
+  }
+
+  fn attr_div(&mut self, data: &[u8]) {
+    let after = data[1] & 0x3;
+    let before = (data[1] >> 2) & 0x3;
+    let on_line = (data[1] >> 4) & 0x3;
+    let horizontal = data[1] & 0x40 > 0;
+    let split = data[2] & 0x1f;
+    for y in 0..18 {
+      for x in 0..20 {
+        let position = if horizontal { y } else { x };
+        let palette = if position < split {
+          before
+        } else if position == split {
+          on_line
+        } else {
+          after
+        };
+        self.attributes[(y as usize) * 20 + (x as usize)] = palette;
+      }
+    }
+  }
+
+  fn attr_chr(&mut self, data: &[u8]) {
+    let mut x = (data[1] as usize) % 20;
+    let mut y = (data[2] as usize) % 18;
+    let count = ((data[3] as usize) | ((data[4] as usize) << 8)).min(20 * 18);
+    let vertical = data[5] & 0x1 > 0;
+    for i in 0..count {
+      let byte = 6 + i / 4;
+      if byte >= data.len() {
+        break;
+      }
+      let palette = (data[byte] >> (6 - (i % 4) * 2)) & 0x3;
+      self.attributes[y * 20 + x] = palette;
+      if vertical {
+        y += 1;
+        if y >= 18 {
+          y = 0;
+          x = (x + 1) % 20;
+        }
+      } else {
+        x += 1;
+        if x >= 20 {
+          x = 0;
+          y = (y + 1) % 18;
+        }
+      }
+    }
+  }
+
+  fn pal_set(&mut self, data: &[u8]) {
+    for p in 0..4 {
+      let index = ((data[1 + p * 2] as usize) | ((data[2 + p * 2] as usize) << 8)) & 0x1ff;
+      self.palettes[p] = self.system_palettes[index];
+    }
+    if data[9] & 0x40 > 0 {
+      self.mask = Mask::None;
+    }
+  }
+
+  pub fn has_pending_transfer(&self) -> bool {
+    return self.pending_transfer != Transfer::None;
+  }
+
+  // Receives the 4KB of tile data that was on screen during a *_TRN command
+  pub fn complete_transfer(&mut self, data: &[u8]) {
+    match self.pending_transfer {
+      Transfer::Palettes => {
+        for p in 0..512 {
+          for c in 0..4 {
+            let i = p * 8 + c * 2;
+            self.system_palettes[p][c] = (data[i] as u16) | ((data[i + 1] as u16) << 8);
+          }
+        }
+      },
+      Transfer::Tiles(half) => {
+        let start = (half as usize) * 0x1000;
+        self.border_tiles[start..start + 0x1000].copy_from_slice(&data[0..0x1000]);
+      },
+      Transfer::Border => {
+        self.border_map.copy_from_slice(&data[0..0x800]);
+        for p in 0..4 {
+          for c in 0..16 {
+            let i = 0x800 + p * 32 + c * 2;
+            self.border_palettes[p][c] = (data[i] as u16) | ((data[i + 1] as u16) << 8);
+          }
+        }
+      },
+      Transfer::None => (),
+    }
+    self.pending_transfer = Transfer::None;
+  }
+
+  // Color for a pixel of the Game Boy screen, given its DMG shade
+  pub fn colorize(&self, x: usize, y: usize, shade: u8) -> u32 {
+    let palette = self.attributes[(y / 8) * 20 + x / 8] as usize;
+    return palette::cgb_color_to_rgb(self.palettes[palette][(shade & 0x3) as usize]);
+  }
+
+  // Draws the border, then places the colorized screen inside it
+  pub fn render(&mut self, screen: &[u8]) {
+    let backdrop = palette::cgb_color_to_rgb(self.palettes[0][0]);
+    for ty in 0..28 {
+      for tx in 0..32 {
+        let entry_index = (ty * 32 + tx) * 2;
+        let entry = (self.border_map[entry_index] as u16) | ((self.border_map[entry_index + 1] as u16) << 8);
+        let tile = (entry & 0xff) as usize;
+        let palette = (((entry >> 10) & 0x7) as usize).wrapping_sub(4) & 0x3;
+        let flip_x = entry & 0x4000 > 0;
+        let flip_y = entry & 0x8000 > 0;
+        for row in 0..8 {
+          let tile_row = if flip_y { 7 - row } else { row };
+          let base = tile * 32 + tile_row * 2;
+          let planes = [
+            self.border_tiles[base],
+            self.border_tiles[base + 1],
+            self.border_tiles[base + 16],
+            self.border_tiles[base + 17],
+          ];
+          for col in 0..8 {
+            let bit = if flip_x { col } else { 7 - col };
+            let mut color = 0;
+            for plane in 0..4 {
+              color |= ((planes[plane] >> bit) & 1) << plane;
+            }
+            let rgb = if color == 0 {
+              backdrop
+            } else {
+              palette::cgb_color_to_rgb(self.border_palettes[palette][color as usize])
+            };
+            self.set_pixel(tx * 8 + col, ty * 8 + row, rgb);
+          }
+        }
+      }
+    }
+
+    if self.mask == Mask::Freeze {
+      // Keep showing the screen from before the freeze
+      self.render_screen(&[]);
+      return;
+    }
+    self.render_screen(screen);
+  }
+
+  fn render_screen(&mut self, screen: &[u8]) {
+    for y in 0..144 {
+      for x in 0..160 {
+        let rgb = match self.mask {
+          Mask::Black => 0,
+          Mask::Color0 => palette::cgb_color_to_rgb(self.palettes[0][0]),
+          _ => {
+            let i = (y * 160 + x) * 4;
+            if screen.len() <= i {
+              continue;
+            }
+            ((screen[i] as u32) << 16) | ((screen[i + 1] as u32) << 8) | (screen[i + 2] as u32)
+          },
+        };
+        self.set_pixel(SCREEN_X + x, SCREEN_Y + y, rgb);
+      }
+    }
+  }
+
+  fn set_pixel(&mut self, x: usize, y: usize, rgb: u32) {
+    let i = (y * BORDER_WIDTH + x) * 4;
+    self.framebuffer[i] = (rgb >> 16) as u8;
+    self.framebuffer[i + 1] = (rgb >> 8) as u8;
+    self.framebuffer[i + 2] = rgb as u8;
+    self.framebuffer[i + 3] = 0xff;
+  }
+
+  pub fn framebuffer_ptr(&mut self) -> *mut u8 {
+    let ptr = &mut self.framebuffer[0] as *mut u8;
+    return ptr;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use vm::sgb::create_sgb;
+  use vm::sgb::Mask;
+  use vm::sgb::Sgb;
+
+  fn send_packet(sgb: &mut Sgb, packet: &[u8; 16]) {
+    sgb.write_joypad(0x00);
+    sgb.write_joypad(0x30);
+    for i in 0..128 {
+      let bit = (packet[i / 8] >> (i % 8)) & 1;
+      sgb.write_joypad(if bit == 1 { 0x10 } else { 0x20 });
+      sgb.write_joypad(0x30);
+    }
+    // Stop bit
+    sgb.write_joypad(0x20);
+    sgb.write_joypad(0x30);
+  }
+
+  #[test]
+  fn pal01() {
+    let mut sgb = create_sgb();
+    let mut packet = [0; 16];
+    packet[0] = (0x00 << 3) | 1;
+    packet[1] = 0x1f; // shared color 0 is red
+    packet[3] = 0xe0;
+    packet[4] = 0x03; // palette 0, color 1 is green
+    packet[13] = 0x00;
+    packet[14] = 0x7c; // palette 1, color 3 is blue
+    send_packet(&mut sgb, &packet);
+    assert_eq!(sgb.palettes[0][0], 0x001f);
+    assert_eq!(sgb.palettes[3][0], 0x001f);
+    assert_eq!(sgb.palettes[0][1], 0x03e0);
+    assert_eq!(sgb.palettes[1][3], 0x7c00);
+    assert_eq!(sgb.colorize(0, 0, 1), 0x00ff00);
+  }
+
+  #[test]
+  fn attr_blk_spans_packets() {
+    let mut sgb = create_sgb();
+    let mut first = [0; 16];
+    first[0] = (0x04 << 3) | 2;
+    first[1] = 3;
+    // Inside only: palette 1 for tiles (1, 1) to (2, 2)
+    first[2..8].copy_from_slice(&[0x1, 0x1, 1, 1, 2, 2]);
+    // Outside only: palette 2
+    first[8..14].copy_from_slice(&[0x4, 0x20, 0, 0, 19, 0]);
+    // Third set starts in this packet and ends in the next
+    first[14] = 0x2;
+    first[15] = 0x0c;
+    let mut second = [0; 16];
+    second[0..4].copy_from_slice(&[10, 10, 12, 12]);
+    send_packet(&mut sgb, &first);
+    assert_eq!(sgb.attributes[1 * 20 + 1], 0);
+    send_packet(&mut sgb, &second);
+    assert_eq!(sgb.attributes[1 * 20 + 1], 2);
+    assert_eq!(sgb.attributes[0], 2);
+    assert_eq!(sgb.attributes[11 * 20 + 11], 2);
+    assert_eq!(sgb.attributes[10 * 20 + 12], 3);
+  }
+
+  #[test]
+  fn attr_lin_div_chr() {
+    let mut sgb = create_sgb();
+    let mut packet = [0; 16];
+    packet[0] = (0x06 << 3) | 1;
+    packet[1] = 0x40 | (2 << 4) | (1 << 2) | 3;
+    packet[2] = 9;
+    send_packet(&mut sgb, &packet);
+    assert_eq!(sgb.attributes[8 * 20], 1);
+    assert_eq!(sgb.attributes[9 * 20], 2);
+    assert_eq!(sgb.attributes[10 * 20], 3);
+
+    let mut packet = [0; 16];
+    packet[0] = (0x05 << 3) | 1;
+    packet[1] = 1;
+    packet[2] = 4 | (1 << 5);
+    send_packet(&mut sgb, &packet);
+    assert_eq!(sgb.attributes[17 * 20 + 4], 1);
+
+    let mut packet = [0; 16];
+    packet[0] = (0x07 << 3) | 1;
+    packet[1] = 19;
+    packet[2] = 0;
+    packet[3] = 2;
+    packet[6] = 0b11100000;
+    send_packet(&mut sgb, &packet);
+    assert_eq!(sgb.attributes[19], 3);
+    assert_eq!(sgb.attributes[20], 2);
+  }
+
+  #[test]
+  fn mask_and_multiplayer() {
+    let mut sgb = create_sgb();
+    let mut packet = [0; 16];
+    packet[0] = (0x17 << 3) | 1;
+    packet[1] = 2;
+    send_packet(&mut sgb, &packet);
+    assert_eq!(sgb.mask, Mask::Black);
+    let mut packet = [0; 16];
+    packet[0] = (0x11 << 3) | 1;
+    packet[1] = 3;
+    send_packet(&mut sgb, &packet);
+    assert_eq!(sgb.player_count, 4);
+  }
+
+  #[test]
+  fn border_transfer_and_render() {
+    let mut sgb = create_sgb();
+    let mut packet = [0; 16];
+    packet[0] = (0x13 << 3) | 1;
+    send_packet(&mut sgb, &packet);
+    assert!(sgb.has_pending_transfer());
+    // Tile 1 uses color 15 everywhere
+    let mut tiles = vec![0; 0x1000];
+    for i in 32..64 {
+      tiles[i] = 0xff;
+    }
+    sgb.complete_transfer(&tiles);
+    assert!(!sgb.has_pending_transfer());
+
+    packet[0] = (0x14 << 3) | 1;
+    send_packet(&mut sgb, &packet);
+    let mut border = vec![0; 0x1000];
+    // Top left tile is tile 1 with palette 5
+    border[0] = 1;
+    border[1] = 5 << 2;
+    border[0x800 + 32 + 30] = 0xff;
+    border[0x800 + 32 + 31] = 0x7f;
+    sgb.complete_transfer(&border);
+
+    let screen = vec![0x80; 160 * 144 * 4];
+    sgb.render(&screen);
+    assert_eq!(&sgb.framebuffer[0..4], &[0xff, 0xff, 0xff, 0xff]);
+    // Transparent border pixels show the backdrop color
+    assert_eq!(&sgb.framebuffer[8 * 4..8 * 4 + 4], &[0xf7, 0xe7, 0xc6, 0xff]);
+    let center = ((40 * 256) + 48) * 4;
+    assert_eq!(&sgb.framebuffer[center..center + 4], &[0x80, 0x80, 0x80, 0xff]);
+  }
+}
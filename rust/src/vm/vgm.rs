@@ -0,0 +1,143 @@
+// Logs APU register writes as a VGM 1.71 file, which external players can
+// replay on their own Game Boy DMG emulation
+
+const VERSION: u32 = 0x171;
+const HEADER_SIZE: usize = 0x100;
+const CLOCK_RATE: u64 = 4194304;
+// VGM timestamps are always in 44100 Hz samples
+const SAMPLE_RATE: u64 = 44100;
+
+const CMD_WAIT: u8 = 0x61;
+const CMD_WAIT_60HZ: u8 = 0x62;
+const CMD_WAIT_50HZ: u8 = 0x63;
+const CMD_END: u8 = 0x66;
+const CMD_WAIT_SHORT: u8 = 0x70;
+const CMD_DMG_WRITE: u8 = 0xb3;
+
+pub struct VgmLogger {
+  commands: Vec<u8>,
+  // Real time APU cycles since logging started
+  cycles: u64,
+  // Samples already covered by wait commands
+  samples: u64,
+}
+
+impl VgmLogger {
+  pub fn add_time(&mut self, cycles: u8) {
+    self.cycles += cycles as u64;
+  }
+
+  // Logs a write to 0xff10-0xff3f, stamped with the current cycle
+  pub fn write(&mut self, addr: u16, value: u8) {
+    if addr < 0xff10 || addr > 0xff3f {
+      return;
+    }
+    self.catch_up();
+    self.commands.push(CMD_DMG_WRITE);
+    self.commands.push((addr - 0xff10) as u8);
+    self.commands.push(value);
+  }
+
+  // Waits until the current cycle. Samples are derived from the total cycle
+  // count each time so rounding never accumulates.
+  fn catch_up(&mut self) {
+    let target = self.cycles * SAMPLE_RATE / CLOCK_RATE;
+    let mut wait = target - self.samples;
+    self.samples = target;
+    while wait > 0 {
+      if wait == 735 {
+        self.commands.push(CMD_WAIT_60HZ);
+        wait = 0;
+      } else if wait == 882 {
+        self.commands.push(CMD_WAIT_50HZ);
+        wait = 0;
+      } else if wait <= 16 {
+        self.commands.push(CMD_WAIT_SHORT + (wait - 1) as u8);
+        wait = 0;
+      } else {
+        let n = wait.min(0xffff);
+        self.commands.push(CMD_WAIT);
+        self.commands.push(n as u8);
+        self.commands.push((n >> 8) as u8);
+        wait -= n;
+      }
+    }
+  }
+
+  // Builds the complete file, waiting out the time since the last write
+  pub fn finish(mut self) -> Vec<u8> {
+    self.catch_up();
+    self.commands.push(CMD_END);
+
+    let mut vgm = vec![0; HEADER_SIZE];
+    vgm[0x00..0x04].copy_from_slice(b"Vgm ");
+    let eof = (HEADER_SIZE + self.commands.len() - 4) as u32;
+    vgm[0x04..0x08].copy_from_slice(&u32_le(eof));
+    vgm[0x08..0x0c].copy_from_slice(&u32_le(VERSION));
+    vgm[0x18..0x1c].copy_from_slice(&u32_le(self.samples as u32));
+    // Offset of the command data, relative to this field
+    vgm[0x34..0x38].copy_from_slice(&u32_le((HEADER_SIZE - 0x34) as u32));
+    vgm[0x80..0x84].copy_from_slice(&u32_le(CLOCK_RATE as u32));
+    vgm.extend_from_slice(&self.commands);
+    return vgm;
+  }
+}
+
+fn u32_le(value: u32) -> [u8; 4] {
+  return [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8];
+}
+
+pub fn create_vgm_logger() -> VgmLogger {
+  return VgmLogger {
+    commands: Vec::new(),
+    cycles: 0,
+    samples: 0,
+  };
+}
+
+#[cfg(test)]
+mod tests {
+  use vm::vgm::create_vgm_logger;
+
+  #[test]
+  fn logs_writes_with_waits() {
+    let mut log = create_vgm_logger();
+    log.write(0xff26, 0x80);
+    // Not an APU register
+    log.write(0xff40, 0x91);
+    // 95 cycles is just short of one sample
+    for _ in 0..19 {
+      log.add_time(5);
+    }
+    log.write(0xff12, 0xf0);
+    log.add_time(1);
+    log.write(0xff30, 0x12);
+    // A frame at 60 Hz, then a full second
+    for _ in 0..(70224 / 4) {
+      log.add_time(4);
+    }
+    log.write(0xff14, 0x87);
+    for _ in 0..(4194304 / 16) {
+      log.add_time(16);
+    }
+    let vgm = log.finish();
+
+    assert_eq!(&vgm[0..4], b"Vgm ");
+    assert_eq!(&vgm[0x08..0x0c], &[0x71, 0x01, 0, 0]);
+    assert_eq!(&vgm[0x34..0x38], &[0xcc, 0, 0, 0]);
+    assert_eq!(&vgm[0x80..0x84], &[0, 0, 0x40, 0]);
+    let total = (95 + 1 + 70224 + 4194304) * 44100u64 / 4194304;
+    assert_eq!(&vgm[0x18..0x1c], &[total as u8, (total >> 8) as u8, 0, 0]);
+    assert_eq!(&vgm[0x100..], &[
+      0xb3, 0x16, 0x80,
+      0xb3, 0x02, 0xf0,
+      0x70, 0xb3, 0x20, 0x12,
+      // The rest of the frame, 738 samples
+      0x61, 0xe2, 0x02, 0xb3, 0x04, 0x87,
+      0x61, 0x44, 0xac,
+      0x66,
+    ][..]);
+    let eof = vgm.len() - 4;
+    assert_eq!(&vgm[0x04..0x08], &[eof as u8, (eof >> 8) as u8, 0, 0]);
+  }
+}
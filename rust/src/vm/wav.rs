@@ -0,0 +1,56 @@
+// Writes 16-bit PCM WAV files from the APU's floating point samples
+
+pub fn encode_pcm16(sample_rate: u32, channels: u16, samples: &[f32]) -> Vec<u8> {
+  let data_size = (samples.len() * 2) as u32;
+  let block_align = channels * 2;
+  let mut wav = Vec::with_capacity(44 + samples.len() * 2);
+  wav.extend_from_slice(b"RIFF");
+  wav.extend_from_slice(&u32_le(36 + data_size));
+  wav.extend_from_slice(b"WAVE");
+
+  wav.extend_from_slice(b"fmt ");
+  wav.extend_from_slice(&u32_le(16));
+  // Uncompressed PCM
+  wav.extend_from_slice(&u16_le(1));
+  wav.extend_from_slice(&u16_le(channels));
+  wav.extend_from_slice(&u32_le(sample_rate));
+  wav.extend_from_slice(&u32_le(sample_rate * block_align as u32));
+  wav.extend_from_slice(&u16_le(block_align));
+  wav.extend_from_slice(&u16_le(16));
+
+  wav.extend_from_slice(b"data");
+  wav.extend_from_slice(&u32_le(data_size));
+  for sample in samples {
+    let value = (sample.max(-1.0).min(1.0) * 32767.0) as i16;
+    wav.extend_from_slice(&u16_le(value as u16));
+  }
+  return wav;
+}
+
+fn u16_le(value: u16) -> [u8; 2] {
+  return [value as u8, (value >> 8) as u8];
+}
+
+fn u32_le(value: u32) -> [u8; 4] {
+  return [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8];
+}
+
+#[cfg(test)]
+mod tests {
+  use vm::wav::encode_pcm16;
+
+  #[test]
+  fn encodes_stereo_pcm() {
+    let wav = encode_pcm16(44100, 2, &[0.0, 1.0, -1.0, 0.5]);
+    assert_eq!(wav.len(), 44 + 8);
+    assert_eq!(&wav[0..4], b"RIFF");
+    assert_eq!(&wav[4..8], &[44, 0, 0, 0]);
+    assert_eq!(&wav[8..16], b"WAVEfmt ");
+    // 2 channels at 44100 Hz, 176400 bytes per second
+    assert_eq!(&wav[22..24], &[2, 0]);
+    assert_eq!(&wav[24..28], &[0x44, 0xac, 0, 0]);
+    assert_eq!(&wav[28..32], &[0x10, 0xb1, 0x02, 0]);
+    assert_eq!(&wav[36..44], &[b'd', b'a', b't', b'a', 8, 0, 0, 0]);
+    assert_eq!(&wav[44..], &[0x00, 0x00, 0xff, 0x7f, 0x01, 0x80, 0xff, 0x3f]);
+  }
+}